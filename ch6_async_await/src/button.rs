@@ -1,5 +1,245 @@
-#[derive(Clone, Copy)]
+use embedded_hal_async::digital::Wait;
+
+use crate::time::{self, Elapsed, TickDuration, TickInstant, Ticker, Timer};
+
+#[derive(Clone, Copy, Debug)]
 pub enum ButtonDirection {
     Left,
     Right,
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonEvent {
+    Pressed,
+    Released,
+}
+
+/// When a `Debounced` button reports a change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebounceMode {
+    /// As soon as the first edge comes in, then anything else is ignored until
+    /// the stable time is up. Snappy, but a glitch on the line will be taken
+    /// as a press.
+    Leading,
+    /// Only once the new level has held for the stable time. Glitches get
+    /// filtered out, at the cost of reporting everything that much later.
+    Trailing,
+}
+
+/// A button on `input` (pressed = low, like the micro:bit's buttons), turned
+/// into a clean series of `ButtonEvent`s by ignoring the bouncing that goes on
+/// for a few milliseconds around every press & release.
+///
+/// Events always alternate, starting with a press.
+pub struct Debounced<P> {
+    input: P,
+    stable_time: TickDuration,
+    mode: DebounceMode,
+    pressed: bool,
+    /// For `Leading`: bounces after the last reported edge are ignored until
+    /// this point.
+    ignore_until: Option<TickInstant>,
+}
+
+impl<P: Wait> Debounced<P> {
+    pub fn new(input: P, stable_time: TickDuration, mode: DebounceMode) -> Self {
+        Self {
+            input,
+            stable_time,
+            mode,
+            pressed: false,
+            ignore_until: None,
+        }
+    }
+
+    /// Waits for the button to change to the opposite of how it last was.
+    ///
+    /// This can be safely dropped part-way through (e.g. by `with_timeout`):
+    /// the change doesn't count until it's been reported.
+    pub async fn next_event(&mut self) -> Result<ButtonEvent, P::Error> {
+        match self.mode {
+            DebounceMode::Leading => {
+                if let Some(ignore_until) = self.ignore_until {
+                    Timer::at(ignore_until).await;
+                    self.ignore_until = None;
+                }
+                self.wait_for_change().await?;
+                self.ignore_until = Some(Ticker::now() + self.stable_time);
+            }
+            DebounceMode::Trailing => loop {
+                self.wait_for_change().await?;
+                // A bounce back inside the stable time means it wasn't a real
+                // change yet, so go round and wait for the next one.
+                let stable_time = self.stable_time;
+                let was_pressed = self.pressed;
+                match time::with_timeout(stable_time, self.wait_for_level(was_pressed)).await {
+                    Err(Elapsed) => break,
+                    Ok(result) => result?,
+                }
+            },
+        }
+        self.pressed = !self.pressed;
+        Ok(if self.pressed {
+            ButtonEvent::Pressed
+        } else {
+            ButtonEvent::Released
+        })
+    }
+
+    async fn wait_for_change(&mut self) -> Result<(), P::Error> {
+        self.wait_for_level(!self.pressed).await
+    }
+
+    async fn wait_for_level(&mut self, pressed: bool) -> Result<(), P::Error> {
+        if pressed {
+            self.input.wait_for_low().await
+        } else {
+            self.input.wait_for_high().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        convert::Infallible,
+        future::{poll_fn, Future},
+        pin::{pin, Pin},
+        rc::Rc,
+        task::{Context, Poll, Waker},
+    };
+
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    /// A button line that the test sets the level of by hand. Starts out high
+    /// (released).
+    #[derive(Clone, Default)]
+    struct FakeInput(Rc<RefCell<FakeLine>>);
+
+    #[derive(Default)]
+    struct FakeLine {
+        low: bool,
+        waker: Option<Waker>,
+    }
+
+    impl FakeInput {
+        fn set_low(&self, low: bool) {
+            let mut line = self.0.borrow_mut();
+            line.low = low;
+            if let Some(waker) = line.waker.take() {
+                waker.wake();
+            }
+        }
+
+        async fn wait_until_low(&self, low: bool) -> Result<(), Infallible> {
+            poll_fn(|cx| {
+                let mut line = self.0.borrow_mut();
+                if line.low == low {
+                    return Poll::Ready(Ok(()));
+                }
+                line.waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    impl ErrorType for FakeInput {
+        type Error = Infallible;
+    }
+
+    impl Wait for FakeInput {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            self.wait_until_low(false).await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            self.wait_until_low(true).await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            unimplemented!("Debounced only waits for levels")
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            unimplemented!("Debounced only waits for levels")
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            unimplemented!("Debounced only waits for levels")
+        }
+    }
+
+    /// Every test polls again after each change it makes, so nobody needs
+    /// waking up.
+    fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn ms(millis: u64) -> TickDuration {
+        TickDuration::millis(millis)
+    }
+
+    #[test]
+    fn leading_reports_the_first_edge_and_ignores_the_bounces() {
+        let input = FakeInput::default();
+        let mut button = Debounced::new(input.clone(), ms(10), DebounceMode::Leading);
+        {
+            let mut event = pin!(button.next_event());
+            assert!(poll(event.as_mut()).is_pending());
+            input.set_low(true);
+            assert_eq!(poll(event), Poll::Ready(Ok(ButtonEvent::Pressed)));
+        }
+
+        let mut event = pin!(button.next_event());
+        input.set_low(false);
+        assert!(poll(event.as_mut()).is_pending());
+        input.set_low(true);
+        time::advance(ms(10));
+        // The bounce back up was ignored, and the button's still down
+        assert!(poll(event.as_mut()).is_pending());
+        input.set_low(false);
+        assert_eq!(poll(event), Poll::Ready(Ok(ButtonEvent::Released)));
+    }
+
+    #[test]
+    fn trailing_waits_for_the_level_to_hold() {
+        let input = FakeInput::default();
+        let mut button = Debounced::new(input.clone(), ms(10), DebounceMode::Trailing);
+        let mut event = pin!(button.next_event());
+        input.set_low(true);
+        assert!(poll(event.as_mut()).is_pending());
+        time::advance(ms(5));
+        // Bouncing back inside the stable time starts the wait over again
+        input.set_low(false);
+        assert!(poll(event.as_mut()).is_pending());
+        input.set_low(true);
+        assert!(poll(event.as_mut()).is_pending());
+        time::advance(ms(5));
+        assert!(poll(event.as_mut()).is_pending());
+        time::advance(ms(5));
+        assert_eq!(poll(event), Poll::Ready(Ok(ButtonEvent::Pressed)));
+    }
+
+    #[test]
+    fn dropped_part_way_leaves_the_change_for_next_time() {
+        let input = FakeInput::default();
+        let mut button = Debounced::new(input.clone(), ms(10), DebounceMode::Trailing);
+        input.set_low(true);
+        {
+            let mut event = pin!(time::with_timeout(ms(5), button.next_event()));
+            assert!(poll(event.as_mut()).is_pending());
+            time::advance(ms(5));
+            assert!(matches!(poll(event), Poll::Ready(Err(Elapsed))));
+        }
+
+        // The press never got reported, so it's still the next event
+        let mut event = pin!(button.next_event());
+        assert!(poll(event.as_mut()).is_pending());
+        time::advance(ms(10));
+        assert_eq!(poll(event), Poll::Ready(Ok(ButtonEvent::Pressed)));
+    }
+}
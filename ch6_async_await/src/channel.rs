@@ -1,86 +1,597 @@
 use core::{
     cell::{Cell, RefCell},
     future::poll_fn,
+    mem,
     task::{Poll, Waker},
 };
 
+use critical_section::Mutex;
+
 /// Storing the `Waker` directly this time, just to see how that works.
 /// There is no more executor dependency, which is nice..
-pub struct Channel<T> {
-    item: Cell<Option<T>>,
-    waker: RefCell<Option<Waker>>,
+///
+/// Holds up to `N` items, which come out in the same order they went in
+/// (FIFO). Once it's full, `Sender::send` waits for a receiver to make some
+/// room, and `Sender::try_send` hands the item straight back.
+///
+/// There can be up to `MAX_HANDLES` senders and as many receivers at a time.
+/// Each item goes to just one receiver, and wakes just one of the receivers
+/// waiting for it (likewise each free space for senders).
+///
+/// The channel counts as closed once all its senders are gone (for receivers,
+/// once they've taken everything that's left) or all its receivers are gone
+/// (for senders). Getting a new sender/receiver from the `Channel` opens it
+/// again. Before the first sender's been got, receivers just wait for one,
+/// and likewise items sent before the first receiver wait in the queue.
+pub struct Channel<T, const N: usize> {
+    queue: RefCell<Queue<T, N>>,
+    receivers: RefCell<WakerSet>,
+    senders: RefCell<WakerSet>,
+    receiver_count: Cell<usize>,
+    sender_count: Cell<usize>,
+    /// Whether there's ever been a receiver, so that no receivers yet doesn't
+    /// count as closed
+    had_receiver: Cell<bool>,
+    had_sender: Cell<bool>,
 }
 
-impl<T> Channel<T> {
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Channel<T, N> {
     pub fn new() -> Self {
         Self {
-            item: Cell::new(None),
-            waker: RefCell::new(None),
+            queue: RefCell::new(Queue::new()),
+            receivers: RefCell::new(WakerSet::new()),
+            senders: RefCell::new(WakerSet::new()),
+            receiver_count: Cell::new(0),
+            sender_count: Cell::new(0),
+            had_receiver: Cell::new(false),
+            had_sender: Cell::new(false),
         }
     }
 
-    pub fn get_sender(&self) -> Sender<T> {
-        Sender { channel: &self }
+    /// Hands out a new sender, unless there are already `MAX_HANDLES` of them
+    pub fn get_sender(&self) -> Result<Sender<'_, T, N>, HandlesExhausted> {
+        let id = self.senders.borrow_mut().claim().ok_or(HandlesExhausted)?;
+        self.sender_count.set(self.sender_count.get() + 1);
+        self.had_sender.set(true);
+        Ok(Sender { channel: self, id })
     }
 
-    pub fn get_receiver(&self) -> Receiver<T> {
-        Receiver {
-            channel: &self,
-            state: ReceiverState::Init,
-        }
+    /// Hands out a new receiver, unless there are already `MAX_HANDLES` of them
+    pub fn get_receiver(&self) -> Result<Receiver<'_, T, N>, HandlesExhausted> {
+        let id = self.receivers.borrow_mut().claim().ok_or(HandlesExhausted)?;
+        self.receiver_count.set(self.receiver_count.get() + 1);
+        self.had_receiver.set(true);
+        Ok(Receiver { channel: self, id })
+    }
+
+    /// All the senders have gone, so nothing more's coming
+    fn senders_gone(&self) -> bool {
+        self.had_sender.get() && self.sender_count.get() == 0
+    }
+
+    /// All the receivers have gone, so nothing more's going to be taken
+    fn receivers_gone(&self) -> bool {
+        self.had_receiver.get() && self.receiver_count.get() == 0
     }
 
-    fn send(&self, item: T) {
-        self.item.replace(Some(item));
-        if let Some(waker) = self.waker.borrow().as_ref() {
-            // Calling `wake()` consumes the waker, which means we'd have to
-            // `clone()` it first, so instead here we use `wake_by_ref()`
-            waker.wake_by_ref();
+    fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        if self.receivers_gone() {
+            return Err(TrySendError::Closed(item));
         }
+        self.queue.borrow_mut().push(item).map_err(TrySendError::Full)?;
+        self.receivers.borrow_mut().wake_one();
+        Ok(())
     }
 
     fn receive(&self) -> Option<T> {
-        self.item.take()
+        let item = self.queue.borrow_mut().pop()?;
+        // There's room for a waiting sender now
+        self.senders.borrow_mut().wake_one();
+        Some(item)
     }
 
-    fn register(&self, waker: Waker) {
-        self.waker.replace(Some(waker));
+    /// Takes `id`'s waker out of the waiting set for `side`. It might
+    /// have been woken up for a space or an item that it's never going to
+    /// use, so if there's still one going, that wake-up is passed on to
+    /// whoever's next.
+    fn stop_waiting(&self, side: Side, id: usize) {
+        let queue = self.queue.borrow();
+        let (mut waiters, still_available) = match side {
+            Side::Sending => (self.senders.borrow_mut(), !queue.is_full()),
+            Side::Receiving => (self.receivers.borrow_mut(), !queue.is_empty()),
+        };
+        waiters.unregister(id);
+        if still_available {
+            waiters.wake_one();
+        }
     }
 }
 
-pub struct Sender<'a, T> {
-    channel: &'a Channel<T>,
+#[derive(Clone, Copy)]
+enum Side {
+    Sending,
+    Receiving,
+}
+
+/// Held by a `send`/`receive` while it's waiting. If it's dropped part-way
+/// (say, by losing a `select_biased!`), its registration is taken out and any
+/// wake-up it was given is passed on, same as for a whole `Sender`/`Receiver`.
+/// Otherwise it could end up with the only wake-up for an item, while a
+/// receiver that's still waiting sleeps on.
+struct Waiting<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    side: Side,
+    id: usize,
 }
 
-impl<T> Sender<'_, T> {
-    pub fn send(&self, item: T) {
-        self.channel.send(item);
+impl<T, const N: usize> Waiting<'_, T, N> {
+    /// For once the wait is over: there's nothing to pass on, since the
+    /// wake-up got used, but the waker still has to go.
+    fn finish(self) {
+        let waiters = match self.side {
+            Side::Sending => &self.channel.senders,
+            Side::Receiving => &self.channel.receivers,
+        };
+        waiters.borrow_mut().unregister(self.id);
+        mem::forget(self);
     }
 }
 
-enum ReceiverState {
-    Init,
-    Wait,
+impl<T, const N: usize> Drop for Waiting<'_, T, N> {
+    fn drop(&mut self) {
+        self.channel.stop_waiting(self.side, self.id);
+    }
 }
 
-pub struct Receiver<'a, T> {
-    channel: &'a Channel<T>,
-    state: ReceiverState,
+/// How many senders (and, separately, receivers) a `Channel` can have at a
+/// time
+pub const MAX_HANDLES: usize = 4;
+
+/// Every sender (or receiver) a `Channel` can hand out was already taken
+#[derive(Debug)]
+pub struct HandlesExhausted;
+
+/// The wakers of everyone waiting on one side of a `Channel`. Every `Sender`
+/// or `Receiver` gets a slot of its own for as long as it's around, and its
+/// id is the slot's index, so there's always room to register. Waking takes
+/// the waker out of its slot, so anyone who's woken but still can't make
+/// progress has to register again.
+struct WakerSet {
+    slots: [Slot; MAX_HANDLES],
 }
 
-impl<T> Receiver<'_, T> {
-    pub async fn receive(&mut self) -> T {
-        poll_fn(|cx| match self.state {
-            ReceiverState::Init => {
-                self.channel.register(cx.waker().clone());
-                self.state = ReceiverState::Wait;
+enum Slot {
+    /// Not handed out to any `Sender`/`Receiver`
+    Free,
+    /// Handed out, but nobody's waiting on it right now
+    Idle,
+    Waiting(Waker),
+}
+
+impl Slot {
+    /// Takes the waker out, if there's one waiting, leaving the slot idle
+    fn take_waker(&mut self) -> Option<Waker> {
+        match mem::replace(self, Slot::Idle) {
+            Slot::Waiting(waker) => Some(waker),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+}
+
+impl WakerSet {
+    fn new() -> Self {
+        Self {
+            slots: [const { Slot::Free }; MAX_HANDLES],
+        }
+    }
+
+    /// Hands out the id of a free slot, if there is one
+    fn claim(&mut self) -> Option<usize> {
+        let id = self.slots.iter().position(|slot| matches!(slot, Slot::Free))?;
+        self.slots[id] = Slot::Idle;
+        Some(id)
+    }
+
+    /// Gives `id`'s slot back, for the next `Sender`/`Receiver` to claim
+    fn release(&mut self, id: usize) {
+        self.slots[id] = Slot::Free;
+    }
+
+    /// Registers `waker` for `id`, replacing whatever `id` registered before.
+    ///
+    /// Registering again is cheap when nothing's changed, so it's done on
+    /// every pending poll. That way, if a `Receiver` gets moved into another
+    /// task, its registration moves with it, instead of the old task's waker
+    /// getting all the wake-ups while the new task sleeps forever.
+    fn register(&mut self, id: usize, waker: &Waker) {
+        match &mut self.slots[id] {
+            Slot::Waiting(registered) if registered.will_wake(waker) => {}
+            slot => *slot = Slot::Waiting(waker.clone()),
+        }
+    }
+
+    fn unregister(&mut self, id: usize) {
+        self.slots[id].take_waker();
+    }
+
+    fn wake_one(&mut self) {
+        if let Some(waker) = self.slots.iter_mut().find_map(Slot::take_waker) {
+            waker.wake();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.slots.iter_mut().filter_map(Slot::take_waker) {
+            waker.wake();
+        }
+    }
+}
+
+/// A fixed-size ring buffer: `len` items, starting at `head` and wrapping
+/// round the end of `items`.
+struct Queue<T, const N: usize> {
+    items: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    const EMPTY: Option<T> = None;
+
+    const fn new() -> Self {
+        // With no room at all, `pop` would index straight off the end
+        const { assert!(N > 0, "A channel needs room for at least one item") };
+        Self {
+            items: [Self::EMPTY; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let item = self.items[self.head].take()?;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(item)
+    }
+}
+
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    id: usize,
+}
+
+/// There are no senders left, and nothing left in the channel from before
+#[derive(Debug)]
+pub struct Closed;
+
+/// Why `try_send` handed the item back
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Queues up `item`, waiting for room first if the channel's full. The
+    /// item's handed back once all the receivers have gone.
+    ///
+    /// Takes `&mut self` since the sender only has the one slot to wait in.
+    pub async fn send(&mut self, item: T) -> Result<(), T> {
+        let waiting = Waiting {
+            channel: self.channel,
+            side: Side::Sending,
+            id: self.id,
+        };
+        let mut item = Some(item);
+        let result = poll_fn(|cx| match self.try_send(item.take().unwrap()) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(closed)) => Poll::Ready(Err(closed)),
+            Err(TrySendError::Full(full)) => {
+                item = Some(full);
+                self.channel.senders.borrow_mut().register(self.id, cx.waker());
                 Poll::Pending
             }
-            ReceiverState::Wait => match self.channel.receive() {
-                Some(item) => Poll::Ready(item),
-                None => Poll::Pending,
+        })
+        .await;
+        waiting.finish();
+        result
+    }
+
+    /// Queues up `item` if there's room and the receivers haven't all gone,
+    /// otherwise hands it back
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_send(item)
+    }
+}
+
+/// A sender that goes away might already have been woken up for the free
+/// space, so that wake-up gets passed on to whoever's next. If it was the last
+/// sender, all the receivers are woken to find out the channel's closed.
+impl<T, const N: usize> Drop for Sender<'_, T, N> {
+    fn drop(&mut self) {
+        let channel = self.channel;
+        channel.sender_count.set(channel.sender_count.get() - 1);
+        channel.stop_waiting(Side::Sending, self.id);
+        channel.senders.borrow_mut().release(self.id);
+        if channel.sender_count.get() == 0 {
+            channel.receivers.borrow_mut().wake_all();
+        }
+    }
+}
+
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    id: usize,
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Waits for the next item, or gives `Err(Closed)` once there are no
+    /// senders left and everything they sent has been taken.
+    pub async fn receive(&mut self) -> Result<T, Closed> {
+        let waiting = Waiting {
+            channel: self.channel,
+            side: Side::Receiving,
+            id: self.id,
+        };
+        let result = poll_fn(|cx| match self.channel.receive() {
+            Some(item) => Poll::Ready(Ok(item)),
+            None if self.channel.senders_gone() => Poll::Ready(Err(Closed)),
+            None => {
+                self.channel.receivers.borrow_mut().register(self.id, cx.waker());
+                Poll::Pending
+            }
+        })
+        .await;
+        waiting.finish();
+        result
+    }
+}
+
+/// Likewise, a receiver that goes away might have been woken up for an item it
+/// never took. If it was the last receiver, all the senders are woken to find
+/// out the channel's closed.
+impl<T, const N: usize> Drop for Receiver<'_, T, N> {
+    fn drop(&mut self) {
+        let channel = self.channel;
+        channel.receiver_count.set(channel.receiver_count.get() - 1);
+        channel.stop_waiting(Side::Receiving, self.id);
+        channel.receivers.borrow_mut().release(self.id);
+        if channel.receiver_count.get() == 0 {
+            channel.senders.borrow_mut().wake_all();
+        }
+    }
+}
+
+/// A `Channel` that can be put in a `static` and fed from interrupt handlers
+/// (or the interrupt executor's tasks), with everything kept behind a
+/// critical section instead of in `Cell`s & `RefCell`s.
+///
+/// Sending never waits, since an interrupt handler can't: `try_send` hands the
+/// item back if the channel's full. Receiving is async, for a thread-mode
+/// task, through the one `StaticReceiver`. With just the one, there's only
+/// ever a single waker to keep track of.
+pub struct StaticChannel<T, const N: usize> {
+    inner: Mutex<RefCell<StaticInner<T, N>>>,
+}
+
+struct StaticInner<T, const N: usize> {
+    queue: Queue<T, N>,
+    receiver_taken: bool,
+    receiver: Option<Waker>,
+}
+
+impl<T, const N: usize> Default for StaticChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> StaticChannel<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(StaticInner {
+                queue: Queue::new(),
+                receiver_taken: false,
+                receiver: None,
+            })),
+        }
+    }
+
+    /// Hands out the receiving end, or `None` if it's already been taken. It
+    /// can be taken again once the `StaticReceiver` is dropped.
+    pub fn take_receiver(&self) -> Option<StaticReceiver<'_, T, N>> {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            if inner.receiver_taken {
+                return None;
             }
+            inner.receiver_taken = true;
+            Some(StaticReceiver { channel: self })
+        })
+    }
+
+    /// Queues up `item` if there's room, otherwise hands it back. Safe to call
+    /// from any interrupt handler.
+    pub fn try_send(&self, item: T) -> Result<(), T> {
+        let receiver = critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            inner.queue.push(item)?;
+            Ok(inner.receiver.take())
+        })?;
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// The receiving end of a `StaticChannel`. There's only ever one of these at a
+/// time (see `StaticChannel::take_receiver`).
+pub struct StaticReceiver<'a, T, const N: usize> {
+    channel: &'a StaticChannel<T, N>,
+}
+
+impl<T, const N: usize> StaticReceiver<'_, T, N> {
+    /// Waits for the next item. The waker is registered again on every pending
+    /// poll, in case the receiver's moved to another task in between.
+    pub async fn receive(&mut self) -> T {
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                let mut inner = self.channel.inner.borrow_ref_mut(cs);
+                if let Some(item) = inner.queue.pop() {
+                    return Poll::Ready(item);
+                }
+                let registered = inner.receiver.as_ref();
+                if !registered.is_some_and(|waker| waker.will_wake(cx.waker())) {
+                    inner.receiver = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            })
         })
         .await
     }
 }
+
+impl<T, const N: usize> Drop for StaticReceiver<'_, T, N> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let mut inner = self.channel.inner.borrow_ref_mut(cs);
+            inner.receiver = None;
+            inner.receiver_taken = false;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Wake},
+    };
+
+    use super::*;
+
+    /// Stands in for a task: just counts how many times it's been woken
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl CountingWaker {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn receiver_moved_to_another_task_is_woken_there() {
+        let channel: Channel<u8, 2> = Channel::new();
+        let sender = channel.get_sender().unwrap();
+        let mut receiver = channel.get_receiver().unwrap();
+        let task_a = Arc::new(CountingWaker::default());
+        let task_b = Arc::new(CountingWaker::default());
+
+        let mut receive = pin!(receiver.receive());
+        let waker_a = Waker::from(task_a.clone());
+        assert!(receive.as_mut().poll(&mut Context::from_waker(&waker_a)).is_pending());
+        // Same `receive()` future, now being polled by another task
+        let waker_b = Waker::from(task_b.clone());
+        assert!(receive.as_mut().poll(&mut Context::from_waker(&waker_b)).is_pending());
+
+        sender.try_send(7).unwrap();
+        assert_eq!(task_a.count(), 0);
+        assert_eq!(task_b.count(), 1);
+        assert!(matches!(
+            receive.as_mut().poll(&mut Context::from_waker(&waker_b)),
+            Poll::Ready(Ok(7))
+        ));
+    }
+
+    #[test]
+    fn senders_past_the_limit_are_refused_until_one_goes() {
+        let channel: Channel<u8, 2> = Channel::new();
+        let mut senders: Vec<_> = (0..MAX_HANDLES).map(|_| channel.get_sender().unwrap()).collect();
+        assert!(channel.get_sender().is_err());
+        senders.pop();
+        assert!(channel.get_sender().is_ok());
+    }
+
+    #[test]
+    fn receiver_waits_for_the_first_sender() {
+        let channel: Channel<u8, 2> = Channel::new();
+        let mut receiver = channel.get_receiver().unwrap();
+        let task = Arc::new(CountingWaker::default());
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        {
+            let mut receive = pin!(receiver.receive());
+            assert!(receive.as_mut().poll(&mut cx).is_pending());
+            channel.get_sender().unwrap().try_send(3).unwrap();
+            assert_eq!(task.count(), 1);
+            assert!(matches!(receive.poll(&mut cx), Poll::Ready(Ok(3))));
+        }
+        // That sender's gone now, so there's nothing more coming
+        let receive = pin!(receiver.receive());
+        assert!(matches!(receive.poll(&mut cx), Poll::Ready(Err(Closed))));
+    }
+
+    #[test]
+    fn static_try_send_hands_the_item_back_when_full() {
+        let channel: StaticChannel<u8, 1> = StaticChannel::new();
+        assert_eq!(channel.try_send(1), Ok(()));
+        assert_eq!(channel.try_send(2), Err(2));
+    }
+
+    #[test]
+    fn static_receiver_is_woken_by_try_send() {
+        let channel: StaticChannel<u8, 1> = StaticChannel::new();
+        let mut receiver = channel.take_receiver().unwrap();
+        assert!(channel.take_receiver().is_none());
+        let task = Arc::new(CountingWaker::default());
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut receive = pin!(receiver.receive());
+        assert!(receive.as_mut().poll(&mut cx).is_pending());
+        channel.try_send(5).unwrap();
+        assert_eq!(task.count(), 1);
+        assert_eq!(receive.poll(&mut cx), Poll::Ready(5));
+    }
+}
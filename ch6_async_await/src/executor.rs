@@ -1,43 +1,93 @@
 use core::{
+    cell::{Cell, UnsafeCell},
     future::Future,
+    marker::PhantomData,
+    mem::{self, align_of, size_of, MaybeUninit},
     pin::Pin,
-    sync::atomic::{AtomicUsize, Ordering},
-    task::{Context, RawWaker, RawWakerVTable, Waker},
+    ptr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
-use cortex_m::asm;
-use heapless::mpmc::Q4;
+use cortex_m::{
+    asm,
+    peripheral::{scb::VectActive, SCB},
+};
+use critical_section::Mutex;
+use microbit::pac::{Interrupt, NVIC};
 use rtt_target::rprintln;
 
+use crate::gpiote;
+
 /// An alternative to storing the waker: just extract the task information
 /// you're looking for via an extension trait that you can implement for `Waker`
 /// Not a great general solution if you want to be compatible with other
-/// executors, but for this project it's fine.
+/// executors, so anything that needs to wake a task later on should hang onto
+/// a `WakeTarget` instead, which falls back to storing the `Waker` itself.
 pub trait ExtWaker {
-    fn task_id(&self) -> usize;
+    fn task_id(&self) -> Option<usize>;
 }
 
 impl ExtWaker for Waker {
-    fn task_id(&self) -> usize {
-        // When "waker-getters" is stabilized, do this instead:
-        // self.as_raw().data() as usize
-        for task_id in 0..NUM_TASKS.load(Ordering::Relaxed) {
-            if get_waker(task_id).will_wake(self) {
-                return task_id;
+    /// Our wakers carry a pointer to the task's slot in `TASKS`, so the task
+    /// id is just the offset of that slot from the start of the array.
+    /// Wakers made by anybody else (another executor, or a combinator like
+    /// `FuturesUnordered` that wraps our waker) give `None`.
+    fn task_id(&self) -> Option<usize> {
+        if !ptr::eq(self.vtable(), &VTABLE) {
+            return None;
+        }
+        // SAFETY:
+        // Our `VTABLE` is only ever paired with pointers into `TASKS`
+        Some(unsafe { task_id_from_ptr(self.data()) })
+    }
+}
+
+/// Whatever is needed to wake the task that polled a future: the task id for
+/// our own wakers (cheap to store, even in an atomic), or a clone of the
+/// `Waker` for foreign ones.
+pub enum WakeTarget {
+    Task(usize),
+    Foreign(Waker),
+}
+
+impl WakeTarget {
+    pub fn wake(self) {
+        match self {
+            WakeTarget::Task(task_id) => wake_task(task_id),
+            WakeTarget::Foreign(waker) => waker.wake(),
+        }
+    }
+}
+
+impl From<&Waker> for WakeTarget {
+    fn from(waker: &Waker) -> Self {
+        match waker.task_id() {
+            Some(task_id) => WakeTarget::Task(task_id),
+            None => {
+                rprintln!("Foreign waker, storing it as-is");
+                WakeTarget::Foreign(waker.clone())
             }
         }
-        panic!("Unknown waker/executor!");
     }
 }
 
 fn get_waker(task_id: usize) -> Waker {
+    let slot: *const TaskSlot = &TASKS[task_id];
     // SAFETY:
-    // Data argument interpreted as an integer, not dereferenced
+    // Data argument points to a slot in the `static TASKS`, so it's valid
+    // forever, and the vtable functions only ever read its address.
     unsafe {
-        Waker::from_raw(RawWaker::new(task_id as *const (), &VTABLE))
+        Waker::from_raw(RawWaker::new(slot as *const (), &VTABLE))
     }
 }
 
+/// SAFETY:
+/// `p` must point to one of the slots in `TASKS`
+unsafe fn task_id_from_ptr(p: *const ()) -> usize {
+    (p as *const TaskSlot).offset_from(TASKS.as_ptr()) as usize
+}
+
 static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
 
 unsafe fn clone(p: *const ()) -> RawWaker {
@@ -47,44 +97,726 @@ unsafe fn clone(p: *const ()) -> RawWaker {
 unsafe fn drop(_p: *const ()) {}
 
 unsafe fn wake(p: *const ()) {
-    wake_task(p as usize);
+    wake_task(task_id_from_ptr(p));
 }
 
 unsafe fn wake_by_ref(p: *const ()) {
-    wake_task(p as usize);
+    wake_task(task_id_from_ptr(p));
 }
 
 pub fn wake_task(task_id: usize) {
     rprintln!("Waking task {}", task_id);
-    if TASK_ID_READY.enqueue(task_id).is_err() {
-        // Being unable to wake a task will likely cause it to become
-        // permanently unresponsive.
-        panic!("Task queue full: can't add task {}", task_id);
+    let Some(slot) = TASKS.get(task_id) else {
+        rprintln!("Bad task id {}!", task_id);
+        return;
+    };
+    let queued = critical_section::with(|_| {
+        slot.run_queue.get().map(|run_queue| (run_queue, slot.priority.get()))
+    });
+    // Nothing to do if the slot is free: that's a leftover wake-up
+    if let Some((run_queue, priority)) = queued {
+        run_queue.enqueue(task_id, priority);
     }
 }
 
-static TASK_ID_READY: Q4<usize> = Q4::new();
-static NUM_TASKS: AtomicUsize = AtomicUsize::new(0);
+/// Ready tasks are kept track of by priority. Which level gets served next is
+/// decided by `RunQueue::next_ready_task`.
+#[derive(Clone, Copy, Debug)]
+pub enum Priority {
+    /// For tasks that need to react quickly, like handling input
+    High,
+    Normal,
+    /// For housekeeping that can wait until things are quiet
+    Low,
+}
 
-pub fn run_tasks(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> ! {
-    NUM_TASKS.store(tasks.len(), Ordering::Relaxed);
+const NUM_PRIORITIES: usize = 3;
+/// How many tasks in a row a priority level gets to run while the levels below
+/// it may be waiting.
+const FAIRNESS_BUDGET: u8 = 4;
 
-    // everybody gets one run to start...
-    for task_id in 0..tasks.len() {
-        TASK_ID_READY.enqueue(task_id).ok();
+/// Instead of a queue, one "ready" bit per task. Waking a task that's already
+/// waiting to run just sets its bit again, so repeated wake-ups (say, from a
+/// bouncing button) get folded into one poll, and there's nothing to overflow
+/// no matter how many wake-ups come in.
+struct ReadySet {
+    bits: AtomicU32,
+    /// Where the next search for a set bit starts, so that tasks at the same
+    /// level take turns instead of the lowest task id always winning.
+    next: AtomicUsize,
+}
+
+impl ReadySet {
+    const fn new() -> Self {
+        Self {
+            bits: AtomicU32::new(0),
+            next: AtomicUsize::new(0),
+        }
     }
 
-    loop {
-        while let Some(task_id) = TASK_ID_READY.dequeue() {
-            if task_id >= tasks.len() {
-                rprintln!("Bad task id {}!", task_id);
-                continue;
+    fn insert(&self, task_id: usize) {
+        self.bits.fetch_or(1 << task_id, Ordering::Release);
+    }
+
+    fn take(&self) -> Option<usize> {
+        let bits = self.bits.load(Ordering::Acquire);
+        if bits == 0 {
+            return None;
+        }
+        let from_next = bits & (u32::MAX << self.next.load(Ordering::Relaxed));
+        let task_id = if from_next != 0 { from_next } else { bits }.trailing_zeros() as usize;
+        // Cleared before the task is polled, so a wake-up during the poll
+        // means it runs again.
+        self.bits.fetch_and(!(1 << task_id), Ordering::AcqRel);
+        self.next.store((task_id + 1) % MAX_TASKS, Ordering::Relaxed);
+        Some(task_id)
+    }
+}
+
+/// The ready tasks of one executor, plus the interrupt to pend whenever a
+/// task becomes ready (`None` for thread mode, where `wfi` returns on any
+/// interrupt anyway).
+struct RunQueue {
+    ready: [ReadySet; NUM_PRIORITIES],
+    interrupt: Mutex<Cell<Option<Interrupt>>>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            ready: [const { ReadySet::new() }; NUM_PRIORITIES],
+            interrupt: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    fn enqueue(&self, task_id: usize, priority: Priority) {
+        self.ready[priority as usize].insert(task_id);
+        if let Some(interrupt) = critical_section::with(|cs| self.interrupt.borrow(cs).get()) {
+            NVIC::pend(interrupt);
+        }
+    }
+
+    /// Picks the next task to run, from `level` or below. Higher priority
+    /// always goes first, with one exception to stop a busy level from
+    /// starving everything below it: once a level has run `FAIRNESS_BUDGET`
+    /// tasks in a row, it gives its next turn to the levels below, if any of
+    /// them have a task ready. A level's budget is topped up whenever its
+    /// queue runs dry, or after giving way.
+    ///
+    /// The turn that's given away is picked the same way, starting one level
+    /// down, so it's charged to whichever level takes it. That way a level that
+    /// only ever runs on turns given to it still uses up its budget, and passes
+    /// every `FAIRNESS_BUDGET + 1`th one on down in turn.
+    ///
+    /// So under full load, the lower levels still make progress, just slower:
+    /// `Normal` gets at least one turn in every `FAIRNESS_BUDGET + 1`, and
+    /// `Low` one in every `(FAIRNESS_BUDGET + 1)²`. With all three levels busy
+    /// and a budget of 4, every 25 turns go 20 to `High`, 4 to `Normal` and 1
+    /// to `Low`.
+    fn next_ready_task(&self, level: usize, budgets: &mut [u8; NUM_PRIORITIES]) -> Option<usize> {
+        if level == NUM_PRIORITIES {
+            return None;
+        }
+        if budgets[level] == 0 {
+            budgets[level] = FAIRNESS_BUDGET;
+            let lower = self.next_ready_task(level + 1, budgets);
+            if lower.is_some() {
+                return lower;
+            }
+        }
+        match self.ready[level].take() {
+            Some(task_id) => {
+                budgets[level] -= 1;
+                Some(task_id)
+            }
+            None => {
+                budgets[level] = FAIRNESS_BUDGET;
+                self.next_ready_task(level + 1, budgets)
+            }
+        }
+    }
+
+    /// Polls ready tasks until there are none left
+    fn run_ready_tasks(&'static self) {
+        let mut budgets = [FAIRNESS_BUDGET; NUM_PRIORITIES];
+        while let Some(task_id) = self.next_ready_task(0, &mut budgets) {
+            self.poll_task(task_id);
+        }
+    }
+
+    fn poll_task(&'static self, task_id: usize) {
+        let Some(slot) = TASKS.get(task_id) else {
+            rprintln!("Bad task id {}!", task_id);
+            return;
+        };
+        let poll = critical_section::with(|_| {
+            // Leftover wake-ups for a task that has finished (and maybe been
+            // replaced by one on another executor) are skipped.
+            let is_ours = slot.run_queue.get().is_some_and(|run_queue| ptr::eq(run_queue, self));
+            let poll = slot.poll.get().filter(|_| is_ours && !slot.polling.get());
+            slot.polling.set(poll.is_some());
+            poll
+        });
+        let Some(poll) = poll else {
+            return;
+        };
+        if critical_section::with(|_| slot.abort_requested.get()) {
+            slot.cancel(task_id);
+            return;
+        }
+        rprintln!("Running task {}", task_id);
+        // SAFETY:
+        // `poll` & `future` were set up together for the same future, and
+        // the future stays put until the slot is released, which can't happen
+        // while `polling` is set.
+        let result = unsafe {
+            poll(slot.future.get(), &mut Context::from_waker(&get_waker(task_id)))
+        };
+        let aborted = critical_section::with(|_| {
+            if result.is_ready() {
+                rprintln!("Task {} done", task_id);
+                slot.polling.set(false);
+                slot.finish();
+                return false;
+            }
+            let aborted = slot.abort_requested.get();
+            slot.polling.set(aborted);
+            aborted
+        });
+        if aborted {
+            slot.cancel(task_id);
+        }
+    }
+}
+
+static THREAD_MODE_QUEUE: RunQueue = RunQueue::new();
+
+/// Total number of tasks, both the ones handed to `run_tasks` and the ones
+/// spawned later on.
+const MAX_TASKS: usize = 8;
+// One bit per task in a `ReadySet`
+const _: () = assert!(MAX_TASKS <= u32::BITS as usize);
+/// Spawned futures are stored in-place in the task arena, so they have to fit
+/// in one of these (and so does their output, once they're done). `async`
+/// blocks are usually smaller than you'd think, but anything holding a big
+/// buffer across an `.await` will be rejected.
+const TASK_SIZE: usize = 256;
+
+type PollFn = unsafe fn(*mut (), &mut Context<'_>) -> Poll<()>;
+type DropFn = unsafe fn(*mut ());
+
+#[repr(align(8))]
+struct TaskMemory {
+    _bytes: [MaybeUninit<u8>; TASK_SIZE],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TaskState {
+    Free,
+    Running,
+    /// Done, but the output is still waiting for its `JoinHandle`
+    Finished,
+    /// Aborted, but the `JoinHandle` hasn't been told yet
+    Cancelled,
+}
+
+/// One slot in the task arena.
+/// `future` either points into this slot's own `memory` (spawned tasks), or at
+/// one of the pinned futures handed to `run_tasks` (which aren't ours to drop).
+struct TaskSlot {
+    state: Cell<TaskState>,
+    poll: Cell<Option<PollFn>>,
+    drop: Cell<Option<DropFn>>,
+    future: Cell<*mut ()>,
+    memory: UnsafeCell<TaskMemory>,
+    /// Set while a `JoinHandle` for the task is alive, in which case the slot
+    /// can't be released until the handle has picked up the output.
+    joined: Cell<bool>,
+    join_waker: Cell<Option<WakeTarget>>,
+    /// The executor the task runs on, `None` when the slot is free
+    run_queue: Cell<Option<&'static RunQueue>>,
+    priority: Cell<Priority>,
+    /// Bumped every time the slot is released, so that an `AbortHandle`
+    /// can't take down some other task that has moved into the slot since.
+    generation: Cell<u32>,
+    /// Set while the executor has the future in hand, polling or dropping it
+    polling: Cell<bool>,
+    /// An `AbortHandle` only asks: the task's own executor drops the future
+    /// the next time it comes round to it.
+    abort_requested: Cell<bool>,
+}
+
+// SAFETY:
+// Tasks can be woken, spawned, joined & aborted from any executor (or
+// interrupt handler), so everything apart from the future itself is only
+// touched inside a critical section. The future is only touched by the
+// executor it belongs to, while `polling` is set, and that includes dropping
+// it when the task's aborted. Once the task's done, its output is dropped
+// inside a critical section.
+unsafe impl Sync for TaskSlot {}
+
+impl TaskSlot {
+    const fn new() -> Self {
+        Self {
+            state: Cell::new(TaskState::Free),
+            poll: Cell::new(None),
+            drop: Cell::new(None),
+            future: Cell::new(ptr::null_mut()),
+            memory: UnsafeCell::new(TaskMemory {
+                _bytes: [MaybeUninit::uninit(); TASK_SIZE],
+            }),
+            joined: Cell::new(false),
+            join_waker: Cell::new(None),
+            run_queue: Cell::new(None),
+            priority: Cell::new(Priority::Normal),
+            generation: Cell::new(0),
+            polling: Cell::new(false),
+            abort_requested: Cell::new(false),
+        }
+    }
+
+    fn occupy(
+        &self,
+        future: *mut (),
+        poll: PollFn,
+        drop: Option<DropFn>,
+        joined: bool,
+        run_queue: &'static RunQueue,
+        priority: Priority,
+    ) {
+        self.run_queue.set(Some(run_queue));
+        self.priority.set(priority);
+        self.future.set(future);
+        self.poll.set(Some(poll));
+        self.drop.set(drop);
+        self.joined.set(joined);
+        self.state.set(TaskState::Running);
+    }
+
+    /// Called once the future has returned `Ready`. It won't be polled again,
+    /// and unless somebody is waiting on the output, the slot is freed up.
+    fn finish(&self) {
+        self.poll.set(None);
+        if self.joined.get() {
+            self.state.set(TaskState::Finished);
+            if let Some(target) = self.join_waker.take() {
+                target.wake();
+            }
+        } else {
+            self.release();
+        }
+    }
+
+    /// Drops the future right where it is, and makes sure nothing is left
+    /// behind that could wake the slot up again. Any `JoinHandle` is told
+    /// about it, otherwise the slot is freed up straight away.
+    ///
+    /// Only for the task's own executor to call, with `polling` set, so the
+    /// future gets dropped in the same context it's been polled in (and
+    /// outside of any critical section, like a poll).
+    fn cancel(&self, task_id: usize) {
+        let drop = critical_section::with(|_| {
+            self.poll.set(None);
+            self.drop.take()
+        });
+        if let Some(drop) = drop {
+            // SAFETY:
+            // `drop` was created alongside `future` for the same type, and
+            // it's been taken out so it can't run twice. Nothing else touches
+            // the future while `polling` is set.
+            unsafe { drop(self.future.get()) };
+        }
+        // Any `Timer`s went with the future, but GPIO channels & port inputs
+        // might only have been borrowed by the task, so they need telling.
+        gpiote::forget_task(task_id);
+        critical_section::with(|_| {
+            self.polling.set(false);
+            if self.joined.get() {
+                self.state.set(TaskState::Cancelled);
+                if let Some(target) = self.join_waker.take() {
+                    target.wake();
+                }
+            } else {
+                self.release();
+            }
+        });
+    }
+
+    /// Drops whatever is left in the slot (if we own it) and frees it up for a
+    /// new task.
+    fn release(&self) {
+        if let Some(drop) = self.drop.take() {
+            // SAFETY:
+            // `drop` was created alongside `future` for the same type, and
+            // the slot is freed right after so it can't be dropped twice.
+            unsafe { drop(self.future.get()) };
+        }
+        self.poll.set(None);
+        self.future.set(ptr::null_mut());
+        self.joined.set(false);
+        self.join_waker.take();
+        self.abort_requested.set(false);
+        self.run_queue.set(None);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.state.set(TaskState::Free);
+    }
+}
+
+static TASKS: [TaskSlot; MAX_TASKS] = [const { TaskSlot::new() }; MAX_TASKS];
+
+/// What actually lives in the memory of a spawned task's slot: the future,
+/// and then its output once it's done.
+enum Stage<F: Future> {
+    Running(F),
+    Finished(F::Output),
+    Consumed,
+}
+
+/// SAFETY:
+/// `stage` must point to a live `Stage<F>` that is never moved afterwards.
+unsafe fn poll_spawned<F: Future>(stage: *mut (), cx: &mut Context<'_>) -> Poll<()> {
+    let stage = &mut *(stage as *mut Stage<F>);
+    let Stage::Running(future) = stage else {
+        return Poll::Ready(());
+    };
+    match Pin::new_unchecked(future).poll(cx) {
+        Poll::Ready(output) => {
+            // Dropping the future in place, so its pinning guarantee holds
+            *stage = Stage::Finished(output);
+            Poll::Ready(())
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+/// SAFETY:
+/// `stage` must point to a live `Stage<F>`, which must not be used again
+/// afterwards.
+unsafe fn drop_spawned<F: Future>(stage: *mut ()) {
+    ptr::drop_in_place(stage as *mut Stage<F>);
+}
+
+/// SAFETY:
+/// `stage` must point to a live `Stage<F>`, which must be `Finished`.
+unsafe fn take_output<F: Future>(stage: *mut ()) -> F::Output {
+    match mem::replace(&mut *(stage as *mut Stage<F>), Stage::Consumed) {
+        Stage::Finished(output) => output,
+        _ => unreachable!("Task output taken before it finished"),
+    }
+}
+
+/// SAFETY:
+/// `future` must point to one of the entries of the slice handed to
+/// `run_tasks`, which is borrowed for as long as the executor runs (forever).
+unsafe fn poll_pinned(future: *mut (), cx: &mut Context<'_>) -> Poll<()> {
+    (*(future as *mut Pin<&mut dyn Future<Output = ()>>))
+        .as_mut()
+        .poll(cx)
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// Every slot in the task arena is taken
+    Full,
+    /// The future (or its output) doesn't fit in a slot (see `TASK_SIZE`)
+    TooLarge,
+}
+
+/// Handle that lets a running task start other tasks on the thread-mode
+/// executor. Those tasks never leave thread mode, so they don't need to be
+/// `Send`, but that means the `Spawner` can't leave thread mode either:
+/// otherwise an interrupt could hand over a future that shares something
+/// (say, an `Rc`) with what it keeps for itself.
+#[derive(Clone, Copy)]
+pub struct Spawner {
+    run_queue: &'static RunQueue,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Spawns onto the thread-mode executor, i.e. `run_tasks`.
+///
+/// Panics if called from an interrupt handler (including an
+/// `InterruptExecutor`'s tasks), for the same reason a `Spawner` isn't `Send`.
+pub fn spawner() -> Spawner {
+    assert!(
+        SCB::vect_active() == VectActive::ThreadMode,
+        "The thread-mode spawner can only be taken in thread mode"
+    );
+    Spawner {
+        run_queue: &THREAD_MODE_QUEUE,
+        _not_send: PhantomData,
+    }
+}
+
+impl Spawner {
+    /// Moves `future` into a free slot of the task arena and schedules its
+    /// first poll. The returned `JoinHandle` can be awaited for the output,
+    /// or dropped to let the task run to completion on its own.
+    pub fn spawn<F>(
+        &self,
+        priority: Priority,
+        future: F,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+    {
+        spawn_on(self.run_queue, priority, future)
+    }
+}
+
+/// Handle that lets a task start other tasks on an `InterruptExecutor`.
+///
+/// Those tasks get polled inside an interrupt handler, which can cut in on
+/// thread mode at any point (say, halfway through a `RefCell` borrow), so
+/// only futures that are `Send`, with a `Send` output, are allowed. Anything
+/// that's shared with thread mode has to go through a critical section
+/// instead, like a `StaticChannel`.
+#[derive(Clone, Copy)]
+pub struct SendSpawner {
+    run_queue: &'static RunQueue,
+}
+
+impl SendSpawner {
+    /// Same as `Spawner::spawn`, apart from the `Send` bounds.
+    pub fn spawn<F>(
+        &self,
+        priority: Priority,
+        future: F,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        spawn_on(self.run_queue, priority, future)
+    }
+}
+
+/// Does the spawning for both kinds of spawner, which have already checked
+/// that `future` is fine to run on `run_queue`.
+fn spawn_on<F>(
+    run_queue: &'static RunQueue,
+    priority: Priority,
+    future: F,
+) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + 'static,
+{
+    if size_of::<Stage<F>>() > TASK_SIZE
+        || align_of::<Stage<F>>() > align_of::<TaskMemory>()
+    {
+        return Err(SpawnError::TooLarge);
+    }
+    let (task_id, generation) = critical_section::with(|_| {
+        let (task_id, slot) = TASKS
+            .iter()
+            .enumerate()
+            .find(|(_, slot)| slot.state.get() == TaskState::Free)
+            .ok_or(SpawnError::Full)?;
+        let memory = slot.memory.get() as *mut Stage<F>;
+        // SAFETY:
+        // The slot is free, so nothing else is using its memory, and the
+        // size & alignment of `Stage<F>` were checked above.
+        unsafe { memory.write(Stage::Running(future)) };
+        slot.occupy(
+            memory as *mut (),
+            poll_spawned::<F>,
+            Some(drop_spawned::<F>),
+            true,
+            run_queue,
+            priority,
+        );
+        Ok((task_id, slot.generation.get()))
+    })?;
+    rprintln!("Spawned task {}", task_id);
+    wake_task(task_id);
+    Ok(JoinHandle {
+        task_id,
+        generation,
+        take_output: take_output::<F>,
+        done: false,
+        _not_send: PhantomData,
+    })
+}
+
+/// The task was aborted before it could finish
+#[derive(Debug)]
+pub struct Cancelled;
+
+/// Awaiting a `JoinHandle` gives the output of the spawned task, or
+/// `Cancelled` if it was aborted. Dropping it detaches the task instead: it
+/// keeps running, and its output is dropped.
+pub struct JoinHandle<T> {
+    task_id: usize,
+    generation: u32,
+    take_output: unsafe fn(*mut ()) -> T,
+    done: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            task_id: self.task_id,
+            generation: self.generation,
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.done {
+            panic!("JoinHandle polled after completion");
+        }
+        let slot = &TASKS[this.task_id];
+        critical_section::with(|_| match slot.state.get() {
+            TaskState::Finished => {
+                // SAFETY:
+                // `take_output` was made for the future living in this slot,
+                // which can't have been reused since we're still holding on.
+                let output = unsafe { (this.take_output)(slot.future.get()) };
+                this.done = true;
+                slot.release();
+                Poll::Ready(Ok(output))
             }
-            rprintln!("Running task {}", task_id);
-            let _ = tasks[task_id]
-                .as_mut()
-                .poll(&mut Context::from_waker(&get_waker(task_id)));
+            TaskState::Cancelled => {
+                this.done = true;
+                slot.release();
+                Poll::Ready(Err(Cancelled))
+            }
+            _ => {
+                slot.join_waker.set(Some(cx.waker().into()));
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
         }
+        let slot = &TASKS[self.task_id];
+        critical_section::with(|_| {
+            slot.joined.set(false);
+            slot.join_waker.take();
+            if matches!(slot.state.get(), TaskState::Finished | TaskState::Cancelled) {
+                // Nobody's going to pick up the output now
+                slot.release();
+            }
+        });
+    }
+}
+
+/// Stops a spawned task for good: its future is dropped in place (taking any
+/// pending timers with it), its GPIO wake-ups are forgotten, and its
+/// `JoinHandle` (if any) gives `Cancelled`. Aborting a task that has already
+/// finished does nothing.
+///
+/// The handle can be used from anywhere, so it doesn't do the dropping itself:
+/// an interrupt could be cutting in on thread mode halfway through using
+/// something the future shares. Instead the task is woken, and its own
+/// executor drops it the next time it comes round, in place of a poll.
+///
+/// The tasks handed to `run_tasks` live on the stack of `main`, so they can't
+/// be aborted. Spawn them instead if they ever need stopping.
+#[derive(Clone, Copy)]
+pub struct AbortHandle {
+    task_id: usize,
+    generation: u32,
+}
+
+impl AbortHandle {
+    pub fn abort(&self) {
+        let slot = &TASKS[self.task_id];
+        let requested = critical_section::with(|_| {
+            let running =
+                slot.generation.get() == self.generation && slot.state.get() == TaskState::Running;
+            if running {
+                slot.abort_requested.set(true);
+            }
+            running
+        });
+        if requested {
+            rprintln!("Aborting task {}", self.task_id);
+            wake_task(self.task_id);
+        }
+    }
+}
+
+/// An executor that polls its tasks from inside an interrupt handler instead
+/// of `run_tasks`, so they preempt everything running in thread mode (and in
+/// any interrupt of lower priority). Waking one of its tasks pends the
+/// interrupt, rather than waiting for the thread-mode loop to come around.
+///
+/// Any spare interrupt will do, but the SWI/EGU ones are there for exactly
+/// this. Its handler has to call `on_interrupt`:
+/// ```ignore
+/// static EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+///
+/// #[interrupt]
+/// fn SWI0_EGU0() {
+///     EXECUTOR.on_interrupt();
+/// }
+/// ```
+pub struct InterruptExecutor {
+    run_queue: RunQueue,
+}
+
+impl InterruptExecutor {
+    pub const fn new() -> Self {
+        Self {
+            run_queue: RunQueue::new(),
+        }
+    }
+
+    /// Sets the interrupt up at the given `level`, from 0 (most urgent) to 7
+    /// (least urgent, but still ahead of thread mode). The nRF52833 only
+    /// implements the top 3 bits of the NVIC priority, hence the shift, and
+    /// anything above 7 would wrap round to an urgent level instead.
+    pub fn start(&'static self, interrupt: Interrupt, level: u8, nvic: &mut NVIC) -> SendSpawner {
+        assert!(level <= 7, "Interrupt priority level {} is out of range (0-7)", level);
+        critical_section::with(|cs| self.run_queue.interrupt.borrow(cs).set(Some(interrupt)));
+        // SAFETY:
+        // We aren't using priority-based critical sections.
+        unsafe {
+            nvic.set_priority(interrupt, level << 5);
+            NVIC::unmask(interrupt);
+        }
+        SendSpawner {
+            run_queue: &self.run_queue,
+        }
+    }
+
+    pub fn on_interrupt(&'static self) {
+        self.run_queue.run_ready_tasks();
+    }
+}
+
+pub fn run_tasks(tasks: &mut [(Priority, Pin<&mut dyn Future<Output = ()>>)]) -> ! {
+    // everybody gets one run to start...
+    for (priority, task) in tasks.iter_mut() {
+        let future = task as *mut Pin<&mut dyn Future<Output = ()>> as *mut ();
+        // Tasks may have been spawned already (e.g. onto an interrupt
+        // executor), so just take whichever slots are free.
+        let task_id = critical_section::with(|_| {
+            let Some((task_id, slot)) = TASKS
+                .iter()
+                .enumerate()
+                .find(|(_, slot)| slot.state.get() == TaskState::Free)
+            else {
+                panic!("Too many tasks (max {})", MAX_TASKS);
+            };
+            slot.occupy(future, poll_pinned, None, false, &THREAD_MODE_QUEUE, *priority);
+            task_id
+        });
+        wake_task(task_id);
+    }
+
+    loop {
+        THREAD_MODE_QUEUE.run_ready_tasks();
         rprintln!("No tasks ready, going to sleep...");
         asm::wfi();
     }
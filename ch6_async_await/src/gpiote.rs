@@ -1,22 +1,77 @@
 use core::{
+    cell::RefCell,
+    convert::Infallible,
     future::poll_fn,
-    sync::atomic::{AtomicUsize, Ordering},
+    mem::ManuallyDrop,
+    ptr,
     task::Poll,
 };
 
-use embedded_hal::digital::{InputPin, PinState};
+use critical_section::{CriticalSection, Mutex};
+use embedded_hal::digital::{ErrorType, InputPin, PinState};
+use embedded_hal_async::digital::Wait;
 use microbit::{
     hal::{
-        gpio::{Floating, Input, Pin},
-        gpiote::Gpiote,
+        gpio::{Floating, Input, Output, Pin, PushPull},
+        gpiote::{Gpiote, GpioteChannel, TaskOutPolarity},
     },
-    pac::{interrupt, Interrupt, NVIC},
+    pac::{interrupt, p0, Interrupt, NVIC, P0, P1},
 };
 
-use crate::executor::{wake_task, ExtWaker};
+use crate::{
+    executor::WakeTarget,
+    ppi::{Event, Task},
+};
+
+/// GPIOTE has 8 channels, each of which can watch one pin
+const NUM_CHANNELS: usize = 8;
+
+/// Every channel was already taken
+#[derive(Debug)]
+pub struct ChannelsExhausted;
+
+/// Marks the first free channel as taken, and hands back its number
+fn claim_channel() -> Result<usize, ChannelsExhausted> {
+    critical_section::with(|cs| {
+        let mut channels = CHANNELS.borrow_ref_mut(cs);
+        let (channel_id, channel) = channels
+            .iter_mut()
+            .enumerate()
+            .find(|(_, channel)| !channel.allocated)
+            .ok_or(ChannelsExhausted)?;
+        *channel = IDLE_CHANNEL;
+        channel.allocated = true;
+        Ok(channel_id)
+    })
+}
+
+/// Turns the channel off and marks it as free again
+fn release_channel(channel_id: usize) {
+    // SAFETY:
+    // The channel's still ours until it's marked free below, and INTENCLR
+    // only affects the bits that are set.
+    let gpiote = unsafe { &*microbit::pac::GPIOTE::ptr() };
+    gpiote.intenclr.write(|w| unsafe { w.bits(1 << channel_id) });
+    gpiote.config[channel_id].reset();
+    critical_section::with(|cs| {
+        gpiote.events_in[channel_id].write(|w| w);
+        CHANNELS.borrow_ref_mut(cs)[channel_id] = IDLE_CHANNEL;
+    });
+}
 
-const MAX_CHANNELS_USED: usize = 2;
-static NEXT_CHANNEL: AtomicUsize = AtomicUsize::new(0);
+fn hal_channel(gpiote: &Gpiote, channel_id: usize) -> GpioteChannel<'_> {
+    match channel_id {
+        0 => gpiote.channel0(),
+        1 => gpiote.channel1(),
+        2 => gpiote.channel2(),
+        3 => gpiote.channel3(),
+        4 => gpiote.channel4(),
+        5 => gpiote.channel5(),
+        6 => gpiote.channel6(),
+        7 => gpiote.channel7(),
+        _ => unreachable!(),
+    }
+}
 
 pub struct InputChannel {
     pin: Pin<Input<Floating>>,
@@ -24,56 +79,423 @@ pub struct InputChannel {
 }
 
 impl InputChannel {
-    pub fn new(pin: Pin<Input<Floating>>, gpiote: &Gpiote) -> Self {
-        let channel_id = NEXT_CHANNEL.fetch_add(1, Ordering::Relaxed);
-        let channel = match channel_id {
-            0 => gpiote.channel0(),
-            1 => gpiote.channel1(),
-            MAX_CHANNELS_USED.. => todo!("Setup more channels!"),
-        };
-        channel.input_pin(&pin).toggle().enable_interrupt();
+    /// Claims the first free GPIOTE channel for `pin`. It's given back (and
+    /// its interrupt turned off) when the `InputChannel` is dropped.
+    pub fn new(pin: Pin<Input<Floating>>, gpiote: &Gpiote) -> Result<Self, ChannelsExhausted> {
+        let channel_id = claim_channel()?;
+        hal_channel(gpiote, channel_id).input_pin(&pin).toggle().enable_interrupt();
         // SAFETY:
         // We aren't using mask-based critical sections.
         unsafe { NVIC::unmask(Interrupt::GPIOTE); }
-        Self {
+        Ok(Self {
             pin,
             channel_id,
+        })
+    }
+
+    /// The channel's IN event, for routing through PPI. It goes off on
+    /// whichever edge the channel was last listening for.
+    pub fn event(&self) -> Event {
+        // SAFETY:
+        // It's an EVENTS register, and we're only taking its address
+        unsafe {
+            let gpiote = &*microbit::pac::GPIOTE::ptr();
+            Event::from_register(&gpiote.events_in[self.channel_id])
         }
     }
 
     pub async fn wait_for(&mut self, ready_state: PinState) {
+        let edge = match ready_state {
+            PinState::High => Edge::Rising,
+            PinState::Low => Edge::Falling,
+        };
+        self.listen_for(edge);
+        poll_fn(|cx| {
+            // Register before checking the level, so an edge that comes in
+            // between can't slip past unnoticed.
+            let target = WakeTarget::from(cx.waker());
+            critical_section::with(|cs| {
+                let mut channels = CHANNELS.borrow_ref_mut(cs);
+                let channel = &mut channels[self.channel_id];
+                if ready_state == PinState::from(self.pin.is_high().unwrap()) {
+                    channel.target = None;
+                    Poll::Ready(())
+                } else {
+                    channel.target = Some(target);
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Waits for the next `edge` *after* this is first polled, regardless of
+    /// the level the pin is at: GPIOTE is set to only raise an event on that
+    /// edge, and it's the event that completes the wait.
+    async fn wait_for_edge(&mut self, edge: Edge) {
+        self.listen_for(edge);
         poll_fn(|cx| {
-            if ready_state == PinState::from(self.pin.is_high().unwrap()) {
-                Poll::Ready(())
-            } else {
-                WAKE_TASKS[self.channel_id].store(cx.waker().task_id(), Ordering::Relaxed);
-                Poll::Pending
+            let target = WakeTarget::from(cx.waker());
+            critical_section::with(|cs| {
+                let mut channels = CHANNELS.borrow_ref_mut(cs);
+                let channel = &mut channels[self.channel_id];
+                if channel.triggered {
+                    channel.target = None;
+                    Poll::Ready(())
+                } else {
+                    channel.target = Some(target);
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Switches the channel's event polarity over to `edge`, and forgets about
+    /// any edges that came before.
+    fn listen_for(&mut self, edge: Edge) {
+        // SAFETY:
+        // Each channel's CONFIG register is only touched by its own
+        // `InputChannel`, and `EVENTS_IN` is only cleared inside a critical
+        // section.
+        let gpiote = unsafe { &*microbit::pac::GPIOTE::ptr() };
+        gpiote.config[self.channel_id].modify(|_, w| match edge {
+            Edge::Rising => w.polarity().lo_to_hi(),
+            Edge::Falling => w.polarity().hi_to_lo(),
+            Edge::Any => w.polarity().toggle(),
+        });
+        critical_section::with(|cs| {
+            gpiote.events_in[self.channel_id].write(|w| w);
+            CHANNELS.borrow_ref_mut(cs)[self.channel_id].triggered = false;
+        });
+    }
+}
+
+impl Drop for InputChannel {
+    fn drop(&mut self) {
+        release_channel(self.channel_id);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+impl ErrorType for InputChannel {
+    type Error = Infallible;
+}
+
+impl Wait for InputChannel {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Edge::Rising).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Edge::Falling).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Edge::Any).await;
+        Ok(())
+    }
+}
+
+/// The three tasks a GPIOTE channel can do to its output pin
+#[derive(Clone, Copy, Debug)]
+pub enum OutputTask {
+    Set,
+    Clear,
+    Toggle,
+}
+
+/// Drives a pin through a GPIOTE channel's tasks, rather than the GPIO
+/// registers. Triggering them from the CPU is no better than `OutputPin`, but
+/// the tasks can also be wired up to events through PPI, so the pin changes
+/// without the CPU getting involved at all.
+pub struct OutputChannel {
+    pin: Pin<Output<PushPull>>,
+    channel_id: usize,
+}
+
+impl OutputChannel {
+    /// Claims the first free GPIOTE channel for `pin`, which starts out at
+    /// `initial`. The channel's given back when the `OutputChannel` is dropped.
+    pub fn new(
+        pin: Pin<Output<PushPull>>,
+        gpiote: &Gpiote,
+        initial: PinState,
+    ) -> Result<Self, ChannelsExhausted> {
+        let channel_id = claim_channel()?;
+        let channel = hal_channel(gpiote, channel_id);
+        // The HAL wants to keep the pin it's configuring, but we need ours
+        // back, so it gets a stand-in for the same pin instead.
+        // SAFETY:
+        // The stand-in is only used to write the channel's CONFIG, and is
+        // dropped along with `task` at the end of this function.
+        let stand_in = unsafe { Pin::<Output<PushPull>>::from_psel_bits(pin.psel_bits()) };
+        let mut task = channel.output_pin(stand_in);
+        // The polarity only matters for the OUT task: SET & CLR do what they
+        // say regardless. It has to be set first, since it's `init_*` that
+        // actually writes the channel's CONFIG.
+        task.task_out_polarity(TaskOutPolarity::Toggle);
+        match initial {
+            PinState::High => task.init_high(),
+            PinState::Low => task.init_low(),
+        }
+        Ok(Self { pin, channel_id })
+    }
+
+    /// Gives the channel back, and the pin along with it. The pin goes back to
+    /// whatever level its own OUT register says.
+    pub fn free(self) -> Pin<Output<PushPull>> {
+        let this = ManuallyDrop::new(self);
+        release_channel(this.channel_id);
+        // SAFETY:
+        // `this` is never used or dropped again, so the pin is only moved out
+        // the once.
+        unsafe { ptr::read(&this.pin) }
+    }
+
+    pub fn trigger(&mut self, task: OutputTask) {
+        self.task(task).trigger();
+    }
+
+    /// The task's endpoint, for routing through PPI
+    pub fn task(&self, task: OutputTask) -> Task {
+        // SAFETY:
+        // They're all TASKS registers, and we're only taking their addresses
+        unsafe {
+            let gpiote = &*microbit::pac::GPIOTE::ptr();
+            match task {
+                OutputTask::Set => Task::from_register(&gpiote.tasks_set[self.channel_id]),
+                OutputTask::Clear => Task::from_register(&gpiote.tasks_clr[self.channel_id]),
+                OutputTask::Toggle => Task::from_register(&gpiote.tasks_out[self.channel_id]),
             }
+        }
+    }
+}
+
+impl Drop for OutputChannel {
+    fn drop(&mut self) {
+        release_channel(self.channel_id);
+    }
+}
+
+/// P0 has 32 pins and P1 has 10, numbered together as they are in PSEL
+/// registers: P1.xx is pin 32 + xx.
+const NUM_PORT_PINS: usize = 42;
+
+/// An input that doesn't need a GPIOTE channel of its own, so there can be as
+/// many as there are pins.
+///
+/// Instead, it sets the pin's SENSE field to the level it's waiting for. The
+/// GPIO peripheral ORs together every pin that's sensing its level into one
+/// DETECT signal, and GPIOTE raises a single PORT event when that goes high.
+/// This works without any high-frequency clock running, so it's also the
+/// lowest power way to wait on a pin.
+///
+/// The catch is that the PORT event doesn't say which pin it was for, so the
+/// ISR checks every pin that's sensing, and turns off SENSE for the ones that
+/// have reached their level. That lets DETECT drop again, ready for the next
+/// PORT event. It has to actually drop, since the event only fires on the way
+/// up: if another pin gets to its level while the ISR's busy, DETECT just
+/// stays high, so the ISR goes round again until there are none left.
+pub struct PortInput<MODE> {
+    pin: Pin<Input<MODE>>,
+}
+
+impl<MODE> PortInput<MODE> {
+    pub fn new(pin: Pin<Input<MODE>>) -> Self {
+        // SAFETY:
+        // INTENSET only affects the bits that are set, and we aren't using
+        // mask-based critical sections.
+        let gpiote = unsafe { &*microbit::pac::GPIOTE::ptr() };
+        gpiote.intenset.write(|w| w.port().set());
+        unsafe { NVIC::unmask(Interrupt::GPIOTE); }
+        Self { pin }
+    }
+
+    fn pin_port(&self) -> usize {
+        self.pin.psel_bits() as usize
+    }
+
+    pub async fn wait_for(&mut self, ready_state: PinState) {
+        let pin_port = self.pin_port();
+        poll_fn(|cx| {
+            let target = WakeTarget::from(cx.waker());
+            critical_section::with(|cs| {
+                let mut targets = PORT_WAKE_TASKS.borrow_ref_mut(cs);
+                if ready_state == PinState::from(self.pin.is_high().unwrap()) {
+                    set_sense(pin_port, None);
+                    targets[pin_port] = None;
+                    Poll::Ready(())
+                } else {
+                    // SENSE is (re)armed every poll: if the pin got there and
+                    // went back again in between, the ISR will have turned it
+                    // off.
+                    set_sense(pin_port, Some(ready_state));
+                    targets[pin_port] = Some(target);
+                    Poll::Pending
+                }
+            })
         })
         .await
     }
 }
 
-const INVALID_TASK_ID: usize = 0xFFFF_FFFF;
-const DEFAULT_TASK: AtomicUsize = AtomicUsize::new(INVALID_TASK_ID);
-static WAKE_TASKS: [AtomicUsize; MAX_CHANNELS_USED] = [DEFAULT_TASK; MAX_CHANNELS_USED];
+impl<MODE> Drop for PortInput<MODE> {
+    fn drop(&mut self) {
+        let pin_port = self.pin_port();
+        critical_section::with(|cs| {
+            set_sense(pin_port, None);
+            PORT_WAKE_TASKS.borrow_ref_mut(cs)[pin_port] = None;
+        });
+    }
+}
+
+/// The GPIO port registers for `pin_port`, along with its pin number within
+/// that port.
+fn port_registers(pin_port: usize) -> (&'static p0::RegisterBlock, usize) {
+    // SAFETY:
+    // Only the PIN_CNF registers of pins wrapped in a `PortInput` are
+    // modified, inside critical sections, and the rest is only read.
+    let port = if pin_port < 32 {
+        unsafe { &*P0::ptr() }
+    } else {
+        unsafe { &*P1::ptr() }
+    };
+    (port, pin_port % 32)
+}
+
+/// Points the pin's SENSE at `level`, or turns it off with `None`. Only to be
+/// called inside a critical section.
+fn set_sense(pin_port: usize, level: Option<PinState>) {
+    let (port, pin) = port_registers(pin_port);
+    port.pin_cnf[pin].modify(|_, w| match level {
+        Some(PinState::High) => w.sense().high(),
+        Some(PinState::Low) => w.sense().low(),
+        None => w.sense().disabled(),
+    });
+}
+
+const NO_TARGET: Option<WakeTarget> = None;
+
+static PORT_WAKE_TASKS: Mutex<RefCell<[Option<WakeTarget>; NUM_PORT_PINS]>> =
+    Mutex::new(RefCell::new([NO_TARGET; NUM_PORT_PINS]));
+
+/// Called for a PORT event: wakes whoever's waiting on a pin that's reached
+/// the level it was sensing for, and stops that pin sensing. Only returns once
+/// a full pass finds nothing, i.e. DETECT is low again.
+fn on_port_event(cs: CriticalSection) {
+    let mut targets = PORT_WAKE_TASKS.borrow_ref_mut(cs);
+    loop {
+        let mut detected = false;
+        for (pin_port, target) in targets.iter_mut().enumerate() {
+            let (port, pin) = port_registers(pin_port);
+            let sense = port.pin_cnf[pin].read().sense();
+            if sense.is_disabled() {
+                continue;
+            }
+            let is_high = port.in_.read().bits() & (1 << pin) != 0;
+            if sense.is_high() == is_high {
+                detected = true;
+                set_sense(pin_port, None);
+                if let Some(target) = target.take() {
+                    target.wake();
+                }
+            }
+        }
+        if !detected {
+            break;
+        }
+    }
+}
+
+/// What the ISR and the waiting `InputChannel` share for each channel
+struct ChannelState {
+    /// Whether an `InputChannel` has claimed this channel
+    allocated: bool,
+    /// Who to wake on the next event
+    target: Option<WakeTarget>,
+    /// Set by the ISR when an event comes in, for the edge waits
+    triggered: bool,
+}
+
+const IDLE_CHANNEL: ChannelState = ChannelState {
+    allocated: false,
+    target: None,
+    triggered: false,
+};
+
+static CHANNELS: Mutex<RefCell<[ChannelState; NUM_CHANNELS]>> =
+    Mutex::new(RefCell::new([IDLE_CHANNEL; NUM_CHANNELS]));
+
+/// Forgets any channel or port pin that was going to wake `task_id`, for when
+/// the task is gone and shouldn't be woken any more.
+pub fn forget_task(task_id: usize) {
+    let is_task = |target: &Option<WakeTarget>| {
+        matches!(target, Some(WakeTarget::Task(id)) if *id == task_id)
+    };
+    critical_section::with(|cs| {
+        for channel in CHANNELS.borrow_ref_mut(cs).iter_mut() {
+            if is_task(&channel.target) {
+                channel.target = None;
+            }
+        }
+        for target in PORT_WAKE_TASKS.borrow_ref_mut(cs).iter_mut() {
+            if is_task(target) {
+                *target = None;
+            }
+        }
+    });
+}
 
 #[interrupt]
 fn GPIOTE() {
     // SAFETY:
-    // Use limited to `events_in` register, which is not accessed elsewhere.
+    // Use limited to the `events_in` registers, which are otherwise only
+    // cleared by `listen_for` inside a critical section, and `events_port`,
+    // which is only accessed here.
     let gpiote = unsafe { &*microbit::pac::GPIOTE::ptr() };
-    for (channel, task) in WAKE_TASKS.iter().enumerate() {
-        if gpiote.events_in[channel].read().bits() != 0 {
-            gpiote.events_in[channel].write(|w| w);
-            // Swap in the INVALID_TASK_ID to prevent the task-ready queue from
-            // getting filled up during debounce.
-            let task_id = task.swap(INVALID_TASK_ID, Ordering::Relaxed);
-            if task_id != INVALID_TASK_ID {
-                wake_task(task_id);
+    for channel in 0..NUM_CHANNELS {
+        // Cleared and noted down together, so `listen_for` can't clear an
+        // event in between and have it counted anyway.
+        let target = critical_section::with(|cs| {
+            if gpiote.events_in[channel].read().bits() == 0 {
+                return None;
             }
+            gpiote.events_in[channel].write(|w| w);
+            let mut channels = CHANNELS.borrow_ref_mut(cs);
+            channels[channel].triggered = true;
+            // Take the wake target out: the waits register again every time
+            // they're polled, so there's no point waking on every bounce.
+            channels[channel].target.take()
+        });
+        if let Some(target) = target {
+            target.wake();
         }
     }
+    critical_section::with(|cs| {
+        if gpiote.events_port.read().bits() != 0 {
+            gpiote.events_port.write(|w| w);
+            on_port_event(cs);
+        }
+    });
     // Dummy read to ensure event flags clear
     // (see nRF52833 Product Specification section 6.1.8)
     let _ = gpiote.events_in[0].read().bits();
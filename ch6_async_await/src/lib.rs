@@ -0,0 +1,13 @@
+#![cfg_attr(not(test), no_std)]
+
+// The channel doesn't touch any hardware, so it lives in here rather than in
+// the binary: that way its tests can run on the host (see Cargo.toml).
+pub mod channel;
+
+// `Debounced` only needs a clock, so it's tested on the host as well, with a
+// fake one standing in for the RTC.
+#[cfg(test)]
+pub mod button;
+#[cfg(test)]
+#[path = "time/fake.rs"]
+pub mod time;
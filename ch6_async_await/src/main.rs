@@ -2,90 +2,233 @@
 #![no_main]
 
 mod button;
-mod channel;
 mod executor;
 mod gpiote;
 mod led;
+mod ppi;
 mod time;
 
 use core::pin::pin;
 
-use button::ButtonDirection;
-use channel::{Channel, Receiver, Sender};
+use button::{ButtonDirection, DebounceMode, Debounced};
 use cortex_m_rt::entry;
 use embedded_hal::digital::{OutputPin, PinState};
+use embedded_hal_async::delay::DelayNs;
+use executor::{Cancelled, InterruptExecutor, Priority, Spawner};
 use fugit::ExtU64;
 use futures::{select_biased, FutureExt};
-use gpiote::InputChannel;
+use gpiote::{InputChannel, OutputChannel, OutputTask, PortInput};
 use led::LedRow;
 use microbit::{
     gpio::NUM_COLS,
     hal::{
-        gpio::{Floating, Input, Output, Pin, PushPull},
+        gpio::{Output, Pin, PullUp, PushPull},
         gpiote::Gpiote,
     },
+    pac::{interrupt, Interrupt},
     Board,
 };
 use panic_rtt_target as _;
-use rtt_target::rtt_init_print;
-use time::Ticker;
+use ppi::PpiChannel;
+use rtt_target::{rprintln, rtt_init_print};
+use time::{with_timeout, Delay, Interval, MissedTickBehavior, Ticker};
+use zero_to_async::channel::{
+    Channel, Closed, Receiver, Sender, StaticChannel, StaticReceiver,
+};
+
+/// Times (in ms) of the heartbeats that haven't been logged yet
+static HEARTBEATS: StaticChannel<u64, 2> = StaticChannel::new();
+
+/// Runs its tasks in the SWI0 interrupt, ahead of everything in `run_tasks`
+static HIGH_PRIORITY_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+fn SWI0_EGU0() {
+    HIGH_PRIORITY_EXECUTOR.on_interrupt();
+}
 
 #[entry]
 fn main() -> ! {
     rtt_init_print!();
     let mut board = Board::take().unwrap();
+    #[cfg(not(feature = "timer1-ticker"))]
     Ticker::init(board.RTC0, &mut board.NVIC);
+    #[cfg(feature = "timer1-ticker")]
+    Ticker::init(board.TIMER1, &mut board.NVIC);
     let gpiote = Gpiote::new(board.GPIOTE);
-    let (col, mut row) = board.display_pins.degrade();
-    row[0].set_high().ok();
-    let button_l = board.buttons.button_a.degrade();
-    let button_r = board.buttons.button_b.degrade();
+    let (col, row) = board.display_pins.degrade();
+    let [mut row0, row1, ..] = row;
+    row0.set_high().ok();
+    let button_l = InputChannel::new(board.buttons.button_a.degrade(), &gpiote).unwrap();
+    let button_r = InputChannel::new(board.buttons.button_b.degrade(), &gpiote).unwrap();
+    let row1 = OutputChannel::new(row1, &gpiote, PinState::Low).unwrap();
+    let ring0 = PortInput::new(board.edge.e00.into_pullup_input().degrade());
 
-    let channel: Channel<ButtonDirection> = Channel::new();
-    let led_task = pin!(led_task(col, channel.get_receiver()));
+    let high_priority_spawner =
+        HIGH_PRIORITY_EXECUTOR.start(Interrupt::SWI0_EGU0, 7, &mut board.NVIC);
+    if let Err(e) = high_priority_spawner.spawn(Priority::Normal, heartbeat()) {
+        rprintln!("Couldn't spawn heartbeat: {:?}", e);
+    }
+
+    // The buttons light up and clear the second row of LEDs by themselves,
+    // through PPI. Their events fire on both press & release (depending on
+    // which edge `wait_for` is after), but setting/clearing twice is harmless.
+    let _row1_on = PpiChannel::new(button_l.event(), row1.task(OutputTask::Set)).unwrap();
+    let _row1_off = PpiChannel::new(button_r.event(), row1.task(OutputTask::Clear)).unwrap();
+
+    let spawner = executor::spawner();
+    // Room for a few presses, in case the LED task falls behind
+    let channel: Channel<ButtonDirection, 4> = Channel::new();
+    let led_task = pin!(led_task(col, channel.get_receiver().unwrap()));
+    // The left button reacts straight away, while the right one waits for the
+    // bouncing to stop first: try both and see which feels better!
+    let button_l = Debounced::new(button_l, 100.millis(), DebounceMode::Leading);
+    let button_r = Debounced::new(button_r, 20.millis(), DebounceMode::Trailing);
     let button_l_task = pin!(button_task(
         button_l,
         ButtonDirection::Left,
-        channel.get_sender(),
-        &gpiote
+        channel.get_sender().unwrap(),
+        spawner,
     ));
     let button_r_task = pin!(button_task(
         button_r,
         ButtonDirection::Right,
-        channel.get_sender(),
-        &gpiote
+        channel.get_sender().unwrap(),
+        spawner,
     ));
 
-    executor::run_tasks(&mut [led_task, button_l_task, button_r_task]);
+    let ring0_task = pin!(ring0_task(ring0, row1));
+    let heartbeat_log = pin!(heartbeat_log(HEARTBEATS.take_receiver().unwrap()));
+
+    // Button presses should be picked up ahead of the LED blinking
+    executor::run_tasks(&mut [
+        (Priority::Normal, led_task),
+        (Priority::High, button_l_task),
+        (Priority::High, button_r_task),
+        (Priority::Low, ring0_task),
+        (Priority::Low, heartbeat_log),
+    ]);
 }
 
 async fn led_task(
     col: [Pin<Output<PushPull>>; NUM_COLS],
-    mut receiver: Receiver<'_, ButtonDirection>,
+    mut receiver: Receiver<'_, ButtonDirection, 4>,
 ) {
     let mut blinker = LedRow::new(col);
+    // Skip ahead if a tick gets missed, rather than blinking in a burst
+    let mut interval = pin!(
+        Interval::new(500.millis()).with_missed_tick_behavior(MissedTickBehavior::Skip)
+    );
     loop {
-        blinker.toggle();
         select_biased! {
-            direction = receiver.receive().fuse() => {
-                blinker.shift(direction);
-            }
-            _ = time::delay(500.millis()).fuse() => {}
+            direction = receiver.receive().fuse() => match direction {
+                Ok(direction) => blinker.shift(direction),
+                Err(Closed) => break,
+            },
+            _ = interval.as_mut().tick().fuse() => {}
         }
+        blinker.toggle();
+    }
+    // All the button tasks have finished, so there's nothing left to listen
+    // for: just keep blinking
+    loop {
+        interval.as_mut().tick().await;
+        blinker.toggle();
     }
 }
 
 async fn button_task(
-    pin: Pin<Input<Floating>>,
+    mut button: Debounced<InputChannel>,
     direction: ButtonDirection,
-    sender: Sender<'_, ButtonDirection>,
-    gpiote: &Gpiote,
+    mut sender: Sender<'_, ButtonDirection, 4>,
+    spawner: Spawner,
 ) {
-    let mut input = InputChannel::new(pin, gpiote);
     loop {
-        input.wait_for(PinState::Low).await;
-        sender.send(direction);
-        time::delay(100.millis()).await;
-        input.wait_for(PinState::High).await;
+        button.next_event().await.unwrap();
+        if sender.send(direction).await.is_err() {
+            rprintln!("LED task has stopped listening, so {:?} button is done", direction);
+            return;
+        }
+        let long_press = spawner.spawn(Priority::Low, long_press(direction));
+        if let Err(e) = &long_press {
+            rprintln!("Couldn't spawn long press detection: {:?}", e);
+        }
+        if with_timeout(10.secs(), button.next_event()).await.is_err() {
+            rprintln!("{:?} button held for 10 s, is it stuck?", direction);
+            button.next_event().await.unwrap();
+        }
+        if let Ok(long_press) = long_press {
+            long_press.abort_handle().abort();
+            match long_press.await {
+                Ok(detected_at) => rprintln!(
+                    "{:?} button released {} ms after long press",
+                    direction,
+                    now_millis() - detected_at,
+                ),
+                Err(Cancelled) => rprintln!("{:?} button short press", direction),
+            }
+        }
+    }
+}
+
+/// Logs whenever ring 0 on the edge connector gets connected to GND, and
+/// toggles the second row of LEDs. It only needs a `PortInput`, leaving the
+/// GPIOTE channels for the buttons & LEDs.
+///
+/// Holding it there for 3 seconds takes row 1 back off its GPIOTE channel,
+/// so nothing (not even the buttons, through PPI) can light it up any more.
+async fn ring0_task(mut ring0: PortInput<PullUp>, mut row1: OutputChannel) {
+    loop {
+        ring0.wait_for(PinState::Low).await;
+        rprintln!("Ring 0 connected to GND");
+        row1.trigger(OutputTask::Toggle);
+        if with_timeout(3.secs(), ring0.wait_for(PinState::High)).await.is_err() {
+            break;
+        }
+    }
+    let mut row1 = row1.free();
+    row1.set_low().ok();
+    rprintln!("Ring 0 held down, row 1 is off for good");
+    loop {
+        ring0.wait_for(PinState::High).await;
+        ring0.wait_for(PinState::Low).await;
+        rprintln!("Ring 0 connected to GND");
+    }
+}
+
+/// One-shot task, spawned fresh on every button press and aborted on release,
+/// so it only gets to finish if the button is held down long enough. Hands
+/// back the time it fired at through its `JoinHandle`.
+async fn long_press(direction: ButtonDirection) -> u64 {
+    Delay.delay_ms(1000).await;
+    rprintln!("{:?} button long press!", direction);
+    now_millis()
+}
+
+fn now_millis() -> u64 {
+    Ticker::now().duration_since_epoch().to_millis()
+}
+
+/// Keeps ticking over in the background, even while the thread-mode tasks are
+/// busy, since it runs on the interrupt executor.
+async fn heartbeat() {
+    // Only the gaps matter here, so a late heartbeat just pushes the rest back
+    let mut interval = pin!(
+        Interval::new_at(Ticker::now() + 5.secs(), 5.secs())
+            .with_missed_tick_behavior(MissedTickBehavior::Delay)
+    );
+    loop {
+        interval.as_mut().tick().await;
+        if HEARTBEATS.try_send(now_millis()).is_err() {
+            rprintln!("Heartbeat log is full!");
+        }
+    }
+}
+
+/// Logs the heartbeats from thread mode. The channel is a `StaticChannel`, since
+/// `heartbeat` sends from the SWI0 interrupt.
+async fn heartbeat_log(mut heartbeats: StaticReceiver<'static, u64, 2>) {
+    loop {
+        rprintln!("Still alive at {} ms", heartbeats.receive().await);
     }
 }
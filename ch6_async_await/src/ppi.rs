@@ -0,0 +1,95 @@
+use core::{cell::Cell, ptr};
+
+use critical_section::Mutex;
+use microbit::pac::PPI;
+
+/// PPI channels 0-19 can be programmed; 20-31 are wired up in hardware already
+const NUM_CHANNELS: usize = 20;
+
+/// Every programmable PPI channel was already taken
+#[derive(Debug)]
+pub struct ChannelsExhausted;
+
+/// Bit `n` is set while PPI channel `n` is in use
+static CHANNELS_IN_USE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// The address of a peripheral's EVENTS register, which PPI can watch
+#[derive(Clone, Copy)]
+pub struct Event(*const u32);
+
+impl Event {
+    /// SAFETY:
+    /// `register` must be one of a peripheral's EVENTS registers.
+    pub unsafe fn from_register<T>(register: &T) -> Self {
+        Self(register as *const T as *const u32)
+    }
+}
+
+/// The address of a peripheral's TASKS register, which PPI (or the CPU) can
+/// trigger
+#[derive(Clone, Copy)]
+pub struct Task(*const u32);
+
+impl Task {
+    /// SAFETY:
+    /// `register` must be one of a peripheral's TASKS registers.
+    pub unsafe fn from_register<T>(register: &T) -> Self {
+        Self(register as *const T as *const u32)
+    }
+
+    /// Starts the task from the CPU
+    pub fn trigger(&self) {
+        // SAFETY:
+        // It's a TASKS register (see `from_register`), and writing 1 to one of
+        // those only starts the task.
+        unsafe { ptr::write_volatile(self.0 as *mut u32, 1) };
+    }
+}
+
+/// Connects an event to a task in hardware: whenever the event fires, PPI
+/// triggers the task straight away, without an interrupt or the CPU even
+/// waking up. The connection lasts until the `PpiChannel` is dropped.
+///
+/// Nothing stops the peripheral on either end being reconfigured (or its
+/// owner being dropped) while the connection is still there, in which case the
+/// task will just be triggered by whatever the event now means.
+pub struct PpiChannel {
+    channel_id: usize,
+}
+
+impl PpiChannel {
+    pub fn new(event: Event, task: Task) -> Result<Self, ChannelsExhausted> {
+        let channel_id = critical_section::with(|cs| {
+            let in_use = CHANNELS_IN_USE.borrow(cs);
+            let channel_id = (!in_use.get()).trailing_zeros() as usize;
+            if channel_id >= NUM_CHANNELS {
+                return Err(ChannelsExhausted);
+            }
+            in_use.set(in_use.get() | 1 << channel_id);
+            Ok(channel_id)
+        })?;
+        // SAFETY:
+        // This channel's EEP/TEP registers are only touched by its own
+        // `PpiChannel`, and CHENSET/CHENCLR only affect the bits that are set.
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.ch[channel_id].eep.write(|w| unsafe { w.bits(event.0 as u32) });
+        ppi.ch[channel_id].tep.write(|w| unsafe { w.bits(task.0 as u32) });
+        ppi.chenset.write(|w| unsafe { w.bits(1 << channel_id) });
+        Ok(Self { channel_id })
+    }
+}
+
+impl Drop for PpiChannel {
+    fn drop(&mut self) {
+        // SAFETY:
+        // See `PpiChannel::new`
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenclr.write(|w| unsafe { w.bits(1 << self.channel_id) });
+        ppi.ch[self.channel_id].eep.reset();
+        ppi.ch[self.channel_id].tep.reset();
+        critical_section::with(|cs| {
+            let in_use = CHANNELS_IN_USE.borrow(cs);
+            in_use.set(in_use.get() & !(1 << self.channel_id));
+        });
+    }
+}
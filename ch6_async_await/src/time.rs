@@ -1,102 +1,195 @@
 use core::{
-    cell::{RefCell, RefMut},
-    future::Future,
+    cell::{RefCell, UnsafeCell},
+    future::{self, Future},
+    marker::PhantomPinned,
     pin::Pin,
-    sync::atomic::{AtomicU32, Ordering},
+    ptr,
     task::{Context, Poll},
 };
 
 use critical_section::Mutex;
+use embedded_hal_async::delay::DelayNs;
 use fugit::{Duration, Instant};
-use heapless::{binary_heap::Min, BinaryHeap};
-use microbit::{
-    hal::{
-        rtc::{RtcCompareReg, RtcInterrupt},
-        Rtc,
-    },
-    pac::{interrupt, NVIC, RTC0},
-};
+use futures::Stream;
 
-use crate::executor::{wake_task, ExtWaker};
-
-type TickInstant = Instant<u64, 1, 32768>;
-type TickDuration = Duration<u64, 1, 32768>;
-
-const MAX_DEADLINES: usize = 8;
-static WAKE_DEADLINES: Mutex<RefCell<BinaryHeap<(u64, usize), Min, MAX_DEADLINES>>> =
-    Mutex::new(RefCell::new(BinaryHeap::new()));
-
-/// Deadlines can only be scheduled in a COMPARE register if they fall within
-/// the current overflow-cycle/epoch, and also are not too close to the current
-/// counter value. (see nRF52833 Product Specification section 6.20.7)
-fn schedule_wakeup(
-    mut rm_deadlines: RefMut<BinaryHeap<(u64, usize), Min, MAX_DEADLINES>>,
-    mut rm_rtc: RefMut<Option<Rtc<RTC0>>>,
-) {
-    let rtc = rm_rtc.as_mut().unwrap();
-    while let Some((deadline, task_id)) = rm_deadlines.peek() {
-        let ovf_count = (*deadline >> 24) as u32;
-        if ovf_count == TICKER.ovf_count.load(Ordering::Relaxed) {
-            let counter = (*deadline & 0xFF_FF_FF) as u32;
-            if counter > (rtc.get_counter() + 1) {
-                rtc.set_compare(RtcCompareReg::Compare0, counter).ok();
-                rtc.enable_event(RtcInterrupt::Compare0);
-            } else {
-                // Wake now if it's too close or already past,
-                // then try again with the next available deadline
-                wake_task(*task_id);
-                rm_deadlines.pop();
-                continue;
-            }
+use crate::executor::WakeTarget;
+
+// The hardware side of things (keeping count of ticks, and getting an interrupt
+// in when the next deadline comes up) lives in a backend module, picked at
+// compile time. Everything else here is built on its `Ticker::now` and
+// `schedule_wakeup`.
+#[cfg(not(feature = "timer1-ticker"))]
+mod rtc;
+#[cfg(not(feature = "timer1-ticker"))]
+use rtc as backend;
+
+#[cfg(feature = "timer1-ticker")]
+mod timer1;
+#[cfg(feature = "timer1-ticker")]
+use timer1 as backend;
+
+use backend::schedule_wakeup;
+pub use backend::{Ticker, TICK_HZ};
+
+pub type TickInstant = Instant<u64, 1, TICK_HZ>;
+pub type TickDuration = Duration<u64, 1, TICK_HZ>;
+
+/// A node in the timer queue, living inside the `Timer` it belongs to.
+struct TimerNode {
+    ticks: u64,
+    target: Option<WakeTarget>,
+    next: *mut TimerNode,
+    linked: bool,
+}
+
+/// An intrusive linked list of `TimerNode`s, sorted by deadline (earliest
+/// first). Every `Timer` brings its own node along, so there's no limit on how
+/// many can be waiting at once.
+///
+/// A `Timer` only links its node once it's been pinned (i.e. polled), and
+/// unlinks it when dropped, so the list only ever points at live nodes.
+struct TimerQueue {
+    head: *mut TimerNode,
+}
+
+// SAFETY:
+// Only ever accessed through `WAKE_DEADLINES`, inside a critical section.
+unsafe impl Send for TimerQueue {}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
         }
-        break;
     }
-    if rm_deadlines.is_empty() {
-        rtc.disable_event(RtcInterrupt::Compare0);
+
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    /// The earliest deadline, in ticks
+    fn peek(&self) -> Option<u64> {
+        // SAFETY:
+        // Linked nodes are always live
+        unsafe { self.head.as_ref().map(|node| node.ticks) }
+    }
+
+    /// Unlinks the node with the earliest deadline, handing back whoever it
+    /// was going to wake.
+    fn pop(&mut self) -> Option<WakeTarget> {
+        // SAFETY:
+        // Linked nodes are always live
+        let node = unsafe { self.head.as_mut()? };
+        self.head = node.next;
+        node.next = ptr::null_mut();
+        node.linked = false;
+        node.target.take()
+    }
+
+    /// Links `node` in behind any deadlines at or before its own.
+    ///
+    /// SAFETY:
+    /// `node` must be unlinked, and must stay put until it's removed again.
+    unsafe fn insert(&mut self, node: *mut TimerNode) {
+        let mut link: *mut *mut TimerNode = &mut self.head;
+        while !(*link).is_null() && (**link).ticks <= (*node).ticks {
+            link = &mut (**link).next;
+        }
+        (*node).next = *link;
+        (*node).linked = true;
+        *link = node;
     }
-}
 
-enum TimerState {
-    Init,
-    Wait,
+    /// SAFETY:
+    /// `node` must be live.
+    unsafe fn remove(&mut self, node: *mut TimerNode) {
+        let mut link: *mut *mut TimerNode = &mut self.head;
+        while !(*link).is_null() {
+            if *link == node {
+                *link = (*node).next;
+                (*node).next = ptr::null_mut();
+                (*node).linked = false;
+                return;
+            }
+            link = &mut (**link).next;
+        }
+    }
 }
 
+static WAKE_DEADLINES: Mutex<RefCell<TimerQueue>> = Mutex::new(RefCell::new(TimerQueue::new()));
+
+/// Completes once `end_time` has passed. The deadline is only put in the
+/// queue on the first poll, since that's when the `Timer` is pinned and its
+/// node can't move anymore.
 pub struct Timer {
     end_time: TickInstant,
-    state: TimerState,
+    node: UnsafeCell<TimerNode>,
+    _pinned: PhantomPinned,
 }
 
+// SAFETY:
+// Once the node is linked into the queue, it's only touched inside critical
+// sections, so it doesn't matter which context the `Timer` itself lives in
+// (e.g. a task on an `InterruptExecutor`).
+unsafe impl Send for Timer {}
+
 impl Timer {
     pub fn new(duration: TickDuration) -> Self {
+        Self::at(Ticker::now() + duration)
+    }
+
+    /// A `Timer` for an absolute point in time, rather than some duration from
+    /// now.
+    pub fn at(end_time: TickInstant) -> Self {
         Self {
-            end_time: Ticker::now() + duration,
-            state: TimerState::Init,
+            end_time,
+            node: UnsafeCell::new(TimerNode {
+                ticks: end_time.ticks(),
+                target: None,
+                next: ptr::null_mut(),
+                linked: false,
+            }),
+            _pinned: PhantomPinned,
         }
     }
 
-    /// Registration places the deadline & its task_id onto a `BinaryHeap`, and
-    /// then will attempt to schedule it (via COMPARE0) if it's earlier than
-    /// the current deadline.
-    fn register(&self, task_id: usize) {
-        let new_deadline = self.end_time.ticks();
+    pub fn deadline(&self) -> TickInstant {
+        self.end_time
+    }
+
+    /// Moves the deadline to `end_time`. The node is taken out of the queue if
+    /// it's in there, and goes back in (at its new spot) on the next poll.
+    pub fn reset(self: Pin<&mut Self>, end_time: TickInstant) {
+        self.unlink();
+        // SAFETY:
+        // `end_time` isn't structurally pinned, and the node is unlinked, so
+        // nothing else is looking at its deadline.
+        let this = unsafe { self.get_unchecked_mut() };
+        this.end_time = end_time;
+        this.node.get_mut().ticks = end_time.ticks();
+    }
+
+    /// Registration links the node into the queue (if it isn't already), and
+    /// then will attempt to schedule it (via COMPARE0) if it's the earliest
+    /// deadline. The wake target is refreshed every time, in case the `Timer`
+    /// is being polled by a different task than before.
+    fn register(self: Pin<&mut Self>, target: WakeTarget) {
+        let node = self.node.get();
         critical_section::with(|cs| {
             let mut rm_deadlines = WAKE_DEADLINES.borrow_ref_mut(cs);
-            let is_earliest = if let Some((next_deadline, _)) = rm_deadlines.peek() {
-                new_deadline < *next_deadline
-            } else {
-                true
-            };
-            if rm_deadlines.push((new_deadline, task_id)).is_err() {
-                // Dropping a deadline in this system can be Very Bad:
-                //  - In the LED task, the LED will stop updating, but may come
-                //    back to life on a button press...
-                //  - In a button task, it will never wake again
-                // `panic` to raise awareness of the issue during development
-                panic!("Deadline dropped for task {}!", task_id);
+            // SAFETY:
+            // The node is only touched inside critical sections, and `self` is
+            // pinned, so it stays put until `drop` unlinks it.
+            unsafe {
+                (*node).target = Some(target);
+                if (*node).linked {
+                    return;
+                }
+                rm_deadlines.insert(node);
             }
             // schedule now if its the earliest
-            if is_earliest {
-                schedule_wakeup(rm_deadlines, TICKER.rtc.borrow_ref_mut(cs));
+            if rm_deadlines.head == node {
+                schedule_wakeup(rm_deadlines, cs);
             }
         });
     }
@@ -104,21 +197,42 @@ impl Timer {
 
 impl Future for Timer {
     type Output = ();
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.state {
-            TimerState::Init => {
-                self.register(cx.waker().task_id());
-                self.state = TimerState::Wait;
-                Poll::Pending
-            }
-            TimerState::Wait => {
-                if Ticker::now() >= self.end_time {
-                    Poll::Ready(())
-                } else {
-                    Poll::Pending
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Ticker::now() >= self.end_time {
+            Poll::Ready(())
+        } else {
+            self.register(cx.waker().into());
+            Poll::Pending
+        }
+    }
+}
+
+/// A `Timer` that gets dropped before its deadline (say, because it lost a
+/// `select_biased!`) has to take its node out of `WAKE_DEADLINES` with it, or
+/// the queue would be left pointing at freed memory.
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.unlink();
+    }
+}
+
+impl Timer {
+    /// Takes the node out of `WAKE_DEADLINES` (if it's in there), and
+    /// reschedules COMPARE0 if it was the earliest deadline.
+    fn unlink(&self) {
+        let node = self.node.get();
+        critical_section::with(|cs| {
+            // SAFETY:
+            // The node is live for as long as `self` is
+            if unsafe { (*node).linked } {
+                let mut rm_deadlines = WAKE_DEADLINES.borrow_ref_mut(cs);
+                let was_earliest = rm_deadlines.head == node;
+                unsafe { rm_deadlines.remove(node) };
+                if was_earliest {
+                    schedule_wakeup(rm_deadlines, cs);
                 }
             }
-        }
+        });
     }
 }
 
@@ -126,81 +240,172 @@ pub async fn delay(duration: TickDuration) {
     Timer::new(duration).await;
 }
 
-static TICKER: Ticker = Ticker {
-    ovf_count: AtomicU32::new(0),
-    rtc: Mutex::new(RefCell::new(None)),
-};
+/// A handle for `delay`, for drivers written against `embedded-hal-async`
+/// instead of our own `time` module.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Delay;
 
-/// Keeps track of time for the system using RTC0, which ticks away at a rate
-/// of 32,768/sec using a low-power oscillator that runs even when the core is
-/// powered down.
-pub struct Ticker {
-    ovf_count: AtomicU32,
-    rtc: Mutex<RefCell<Option<Rtc<RTC0>>>>,
-}
-
-impl Ticker {
-    /// Called on startup to get RTC0 going, then hoists the HAL representation
-    /// of RTC0 into the `static TICKER`, where it can be accessed by the
-    /// interrupt handler function or any `Timer` instance.
-    pub fn init(rtc0: RTC0, nvic: &mut NVIC) {
-        let mut rtc = Rtc::new(rtc0, 0).unwrap();
-        rtc.enable_counter();
-        #[cfg(feature = "trigger-overflow")]
-        {
-            rtc.trigger_overflow();
-            // wait for the counter to initialize with its close-to-overflow
-            // value before going any further, otherwise one of the tasks could
-            // schedule a wakeup that will get skipped over when init happens.
-            while rtc.get_counter() == 0 {}
+impl Delay {
+    /// Waits at least `amount / per_sec` seconds.
+    ///
+    /// The amount is rounded *up* to whole ticks, and then one more tick is
+    /// added on top: `Ticker::now` could be almost at the end of the current
+    /// tick, in which case a deadline N ticks away would only be a little over
+    /// N - 1 ticks off.
+    async fn delay_for(amount: u32, per_sec: u64) {
+        if amount == 0 {
+            return;
         }
-        rtc.enable_event(RtcInterrupt::Overflow);
-        rtc.enable_interrupt(RtcInterrupt::Overflow, Some(nvic));
-        rtc.enable_interrupt(RtcInterrupt::Compare0, Some(nvic));
-        critical_section::with(|cs| {
-            TICKER.rtc.replace(cs, Some(rtc));
-        });
+        let ticks = (amount as u64 * TICK_HZ as u64).div_ceil(per_sec) + 1;
+        delay(TickDuration::from_ticks(ticks)).await;
     }
+}
 
-    /// Get the current time, which is a combination of:
-    ///     - The overflow count (upper 40 bits)
-    ///     - The counter value (lowest 24 bits)
-    ///
-    /// Extra care is needed to ensure the current overflow-count & counter
-    /// value are collected during the same overflow-cycle.
-    pub fn now() -> TickInstant {
-        let ticks = {
-            loop {
-                let ovf_before = TICKER.ovf_count.load(Ordering::SeqCst);
-                let counter = critical_section::with(|cs| {
-                    TICKER.rtc.borrow_ref(cs).as_ref().unwrap().get_counter()
-                });
-                let ovf = TICKER.ovf_count.load(Ordering::SeqCst);
-                if ovf_before == ovf {
-                    break ((ovf as u64) << 24 | counter as u64);
-                }
-            }
-        };
-        TickInstant::from_ticks(ticks)
+impl DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        Self::delay_for(ns, 1_000_000_000).await;
     }
+
+    async fn delay_us(&mut self, us: u32) {
+        Self::delay_for(us, 1_000_000).await;
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        Self::delay_for(ms, 1_000).await;
+    }
+}
+
+/// The deadline passed before the future could complete
+#[derive(Debug)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, giving `Err(Elapsed)` if it hasn't
+/// finished by then (in which case it's dropped without another poll).
+pub fn with_timeout<F: Future>(duration: TickDuration, future: F) -> Timeout<F> {
+    with_deadline(Ticker::now() + duration, future)
+}
+
+/// Like `with_timeout`, but gives up at a fixed point in time instead.
+pub fn with_deadline<F: Future>(deadline: TickInstant, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        timer: Timer::at(deadline),
+    }
+}
+
+/// The future returned by `with_timeout`/`with_deadline`. Its `Timer` goes
+/// wherever it goes, so dropping a `Timeout` early (or once the inner future
+/// wins) takes the deadline out of `WAKE_DEADLINES` too.
+pub struct Timeout<F> {
+    future: F,
+    timer: Timer,
 }
 
-#[interrupt]
-fn RTC0() {
-    critical_section::with(|cs| {
-        let mut rm_rtc = TICKER.rtc.borrow_ref_mut(cs);
-        let rtc = rm_rtc.as_mut().unwrap();
-        if rtc.is_event_triggered(RtcInterrupt::Overflow) {
-            rtc.reset_event(RtcInterrupt::Overflow);
-            TICKER.ovf_count.fetch_add(1, Ordering::Relaxed);
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY:
+        // Both fields are structurally pinned: they're never moved out of
+        // `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let timer = unsafe { Pin::new_unchecked(&mut this.timer) };
+        // The future gets first dibs, so one that's ready right on the deadline
+        // still counts.
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
         }
-        if rtc.is_event_triggered(RtcInterrupt::Compare0) {
-            rtc.reset_event(RtcInterrupt::Compare0);
+        timer.poll(cx).map(|_| Err(Elapsed))
+    }
+}
+
+/// What an `Interval` should do when it's polled late enough that one or more
+/// ticks have already gone by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTickBehavior {
+    /// Fire all the missed ticks back-to-back until caught up, then carry on
+    /// with the original schedule.
+    Burst,
+    /// Fire once now, then start a fresh schedule one period from now.
+    Delay,
+    /// Fire once now, then skip ahead to the next tick on the original
+    /// schedule.
+    Skip,
+}
+
+/// Fires every `period`, on fixed deadlines of `start + n * period`. Unlike
+/// calling `delay` in a loop, the time spent between ticks (doing work, or
+/// waiting to be polled) doesn't push the later ticks back.
+///
+/// The first tick completes straight away. `Interval` holds its own `Timer`,
+/// so it needs to be pinned before use, e.g.:
+///
+/// ```ignore
+/// let mut interval = pin!(Interval::new(500.millis()));
+/// loop {
+///     interval.as_mut().tick().await;
+///     // or `interval.next().await`, via `StreamExt`
+/// }
+/// ```
+pub struct Interval {
+    timer: Timer,
+    period: TickDuration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl Interval {
+    pub fn new(period: TickDuration) -> Self {
+        Self::new_at(Ticker::now(), period)
+    }
+
+    /// An `Interval` whose first tick is at `start`.
+    pub fn new_at(start: TickInstant, period: TickDuration) -> Self {
+        assert!(period.ticks() > 0, "Interval period must be non-zero");
+        Self {
+            timer: Timer::at(start),
+            period,
+            missed_tick_behavior: MissedTickBehavior::Burst,
+        }
+    }
+
+    pub fn with_missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+        self.missed_tick_behavior = behavior;
+        self
+    }
+
+    /// Completes at the next tick, with the instant it was scheduled for (which
+    /// may be a little earlier than when it actually gets polled).
+    pub fn tick(self: Pin<&mut Self>) -> impl Future<Output = TickInstant> + '_ {
+        let mut this = self;
+        future::poll_fn(move |cx| this.as_mut().poll_tick(cx))
+    }
+
+    pub fn poll_tick(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TickInstant> {
+        // SAFETY:
+        // `timer` is structurally pinned: it's never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut timer = unsafe { Pin::new_unchecked(&mut this.timer) };
+        if timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
         }
+        let deadline = timer.deadline();
+        let now = Ticker::now();
+        let next = match this.missed_tick_behavior {
+            MissedTickBehavior::Burst => deadline + this.period,
+            MissedTickBehavior::Delay => now + this.period,
+            MissedTickBehavior::Skip => {
+                let missed = (now - deadline).ticks() / this.period.ticks();
+                deadline + TickDuration::from_ticks(this.period.ticks() * (missed + 1))
+            }
+        };
+        timer.reset(next);
+        Poll::Ready(deadline)
+    }
+}
 
-        // For OVF & COMPARE0 events, schedule the next wakeup. This should also
-        // kill enough clock cycles to allow the event flags to clear.
-        // (see nRF52833 Product Specification section 6.1.8)
-        schedule_wakeup(WAKE_DEADLINES.borrow_ref_mut(cs), rm_rtc);
-    });
+impl Stream for Interval {
+    type Item = TickInstant;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_tick(cx).map(Some)
+    }
 }
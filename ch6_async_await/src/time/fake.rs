@@ -0,0 +1,90 @@
+//! Stands in for `time` in the host tests, so `Debounced` can be tested
+//! without an RTC. The clock only moves when a test calls `advance`, and each
+//! test thread gets a clock of its own.
+
+use std::{
+    cell::{Cell, RefCell},
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+};
+
+use fugit::{Duration, Instant};
+
+pub const TICK_HZ: u32 = 1_000;
+
+pub type TickInstant = Instant<u64, 1, TICK_HZ>;
+pub type TickDuration = Duration<u64, 1, TICK_HZ>;
+
+thread_local! {
+    static NOW: Cell<u64> = const { Cell::new(0) };
+    /// Every pending `Timer`'s end time & waker. Dropped timers are left in,
+    /// and just wake their task for nothing once they're up.
+    static TIMERS: RefCell<Vec<(TickInstant, Waker)>> = const { RefCell::new(Vec::new()) };
+}
+
+pub struct Ticker;
+
+impl Ticker {
+    pub fn now() -> TickInstant {
+        TickInstant::from_ticks(NOW.get())
+    }
+}
+
+/// Moves the clock on by `duration`, waking every timer that's up by then
+pub fn advance(duration: TickDuration) {
+    NOW.set(NOW.get() + duration.ticks());
+    let now = Ticker::now();
+    let mut due = Vec::new();
+    TIMERS.with_borrow_mut(|timers| {
+        timers.retain(|(end_time, waker)| {
+            if *end_time > now {
+                return true;
+            }
+            due.push(waker.clone());
+            false
+        })
+    });
+    due.into_iter().for_each(Waker::wake);
+}
+
+pub struct Timer {
+    end_time: TickInstant,
+}
+
+impl Timer {
+    pub fn at(end_time: TickInstant) -> Self {
+        Self { end_time }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Ticker::now() >= self.end_time {
+            return Poll::Ready(());
+        }
+        TIMERS.with_borrow_mut(|timers| timers.push((self.end_time, cx.waker().clone())));
+        Poll::Pending
+    }
+}
+
+/// The deadline passed before the future could complete
+#[derive(Debug)]
+pub struct Elapsed;
+
+/// Same as the real one: the future gets first dibs, then the deadline.
+pub async fn with_timeout<F: Future>(
+    duration: TickDuration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut timer = Timer::at(Ticker::now() + duration);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut timer).poll(cx).map(|_| Err(Elapsed))
+    })
+    .await
+}
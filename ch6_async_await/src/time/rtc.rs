@@ -0,0 +1,187 @@
+use core::{
+    cell::{RefCell, RefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use critical_section::{CriticalSection, Mutex};
+use microbit::{
+    hal::{
+        rtc::{RtcCompareReg, RtcInterrupt},
+        Rtc,
+    },
+    pac::{interrupt, NVIC, RTC0},
+};
+
+use super::{TickInstant, TimerQueue, WAKE_DEADLINES};
+
+/// How many times a second `Ticker` ticks, picked with one of the `tick-hz-*`
+/// cargo features (32,768 if none are enabled). RTC0 always runs off the same
+/// 32.768kHz clock, so slowing it down with the prescaler trades away
+/// resolution for a longer lap of the 24-bit counter: 512 sec at 32,768Hz, but
+/// over 4.5 hours at 1,024Hz. Lower rates also mean fewer OVF & COMPARE3
+/// interrupts waking the core up.
+pub const TICK_HZ: u32 = if cfg!(feature = "tick-hz-16384") {
+    16_384
+} else if cfg!(feature = "tick-hz-8192") {
+    8_192
+} else if cfg!(feature = "tick-hz-4096") {
+    4_096
+} else if cfg!(feature = "tick-hz-2048") {
+    2_048
+} else if cfg!(feature = "tick-hz-1024") {
+    1_024
+} else {
+    32_768
+};
+
+#[cfg(any(
+    all(feature = "tick-hz-16384", feature = "tick-hz-8192"),
+    all(feature = "tick-hz-16384", feature = "tick-hz-4096"),
+    all(feature = "tick-hz-16384", feature = "tick-hz-2048"),
+    all(feature = "tick-hz-16384", feature = "tick-hz-1024"),
+    all(feature = "tick-hz-8192", feature = "tick-hz-4096"),
+    all(feature = "tick-hz-8192", feature = "tick-hz-2048"),
+    all(feature = "tick-hz-8192", feature = "tick-hz-1024"),
+    all(feature = "tick-hz-4096", feature = "tick-hz-2048"),
+    all(feature = "tick-hz-4096", feature = "tick-hz-1024"),
+    all(feature = "tick-hz-2048", feature = "tick-hz-1024"),
+))]
+compile_error!("Only one of the `tick-hz-*` features can be enabled at a time");
+
+/// The counter increments every (PRESCALER + 1) cycles of the 32.768kHz clock
+const PRESCALER: u32 = 32_768 / TICK_HZ - 1;
+
+/// The RTC counter is only 24 bits wide, so a deadline can only go in COMPARE0
+/// once it's less than 3/4 of a lap (0xC0_00_00 ticks) away: any further and
+/// the counter could pass that value on an earlier lap. Those get picked up
+/// later, when the half-period COMPARE3 event comes round. Deadlines also
+/// can't be too close to the current counter value, since COMPARE won't fire
+/// for N+0 or N+1. (see nRF52833 Product Specification section 6.20.7)
+pub(super) fn schedule_wakeup(mut rm_deadlines: RefMut<TimerQueue>, cs: CriticalSection) {
+    let mut rm_rtc = TICKER.rtc.borrow_ref_mut(cs);
+    let rtc = rm_rtc.as_mut().unwrap();
+    while let Some(deadline) = rm_deadlines.peek() {
+        let now = calc_now(TICKER.period.load(Ordering::Relaxed), rtc.get_counter());
+        if deadline > now + 2 {
+            if deadline - now < 0xC0_00_00 {
+                let counter = (deadline & 0xFF_FF_FF) as u32;
+                rtc.set_compare(RtcCompareReg::Compare0, counter).ok();
+                rtc.enable_event(RtcInterrupt::Compare0);
+            } else {
+                rtc.disable_event(RtcInterrupt::Compare0);
+            }
+        } else {
+            // Wake now if it's too close or already past,
+            // then try again with the next available deadline
+            if let Some(target) = rm_deadlines.pop() {
+                target.wake();
+            }
+            continue;
+        }
+        break;
+    }
+    if rm_deadlines.is_empty() {
+        rtc.disable_event(RtcInterrupt::Compare0);
+    }
+}
+
+static TICKER: Ticker = Ticker {
+    period: AtomicU32::new(0),
+    rtc: Mutex::new(RefCell::new(None)),
+};
+
+/// Keeps track of time for the system using RTC0, which ticks away at a rate
+/// of `TICK_HZ` using a low-power oscillator that runs even when the core is
+/// powered down.
+///
+/// Rather than counting overflows, `period` counts *half* laps of the 24-bit
+/// counter: it's bumped by the OVF event (counter back to 0) and by COMPARE3,
+/// which sits at the halfway mark (0x80_00_00). That way `period` is even
+/// whenever the counter is in its bottom half, and odd in the top half.
+pub struct Ticker {
+    period: AtomicU32,
+    rtc: Mutex<RefCell<Option<Rtc<RTC0>>>>,
+}
+
+/// Combines a `period` and a counter value into a 64-bit tick count.
+///
+/// If the counter's MSB doesn't match what `period` says it should be, then
+/// the counter has moved on into the next half-period and the interrupt that
+/// bumps `period` just hasn't run yet. Flipping the MSB with an XOR accounts
+/// for that, so `now` is right even while an OVF is still pending, and doesn't
+/// depend on which order the RTC0 events get handled in.
+fn calc_now(period: u32, counter: u32) -> u64 {
+    ((period as u64) << 23) + (counter ^ ((period & 1) << 23)) as u64
+}
+
+impl Ticker {
+    /// Called on startup to get RTC0 going, then hoists the HAL representation
+    /// of RTC0 into the `static TICKER`, where it can be accessed by the
+    /// interrupt handler function or any `Timer` instance.
+    pub fn init(rtc0: RTC0, nvic: &mut NVIC) {
+        let mut rtc = Rtc::new(rtc0, PRESCALER).unwrap();
+        rtc.set_compare(RtcCompareReg::Compare3, 0x80_00_00).unwrap();
+        rtc.enable_counter();
+        #[cfg(feature = "trigger-overflow")]
+        {
+            rtc.trigger_overflow();
+            // wait for the counter to initialize with its close-to-overflow
+            // value before going any further, otherwise one of the tasks could
+            // schedule a wakeup that will get skipped over when init happens.
+            while rtc.get_counter() == 0 {}
+            // the counter jumped straight into its top half, skipping
+            // COMPARE3, so bump the period by hand.
+            TICKER.period.store(1, Ordering::Relaxed);
+        }
+        rtc.enable_event(RtcInterrupt::Overflow);
+        rtc.enable_event(RtcInterrupt::Compare3);
+        rtc.enable_interrupt(RtcInterrupt::Overflow, Some(nvic));
+        rtc.enable_interrupt(RtcInterrupt::Compare0, Some(nvic));
+        rtc.enable_interrupt(RtcInterrupt::Compare3, Some(nvic));
+        critical_section::with(|cs| {
+            TICKER.rtc.replace(cs, Some(rtc));
+        });
+    }
+
+    /// Get the current time, from the half-period count (upper 41 bits) and
+    /// the counter value (lowest 23 bits). See `calc_now` for how the two are
+    /// kept in step.
+    ///
+    /// `period` has to be read before the counter: read the other way round,
+    /// an interrupt in between could bump `period` for a counter value that's
+    /// already out of date.
+    pub fn now() -> TickInstant {
+        let period = TICKER.period.load(Ordering::SeqCst);
+        let counter = critical_section::with(|cs| {
+            TICKER.rtc.borrow_ref(cs).as_ref().unwrap().get_counter()
+        });
+        TickInstant::from_ticks(calc_now(period, counter))
+    }
+}
+
+#[interrupt]
+fn RTC0() {
+    critical_section::with(|cs| {
+        let mut rm_rtc = TICKER.rtc.borrow_ref_mut(cs);
+        let rtc = rm_rtc.as_mut().unwrap();
+        if rtc.is_event_triggered(RtcInterrupt::Overflow) {
+            rtc.reset_event(RtcInterrupt::Overflow);
+            TICKER.period.fetch_add(1, Ordering::SeqCst);
+        }
+        if rtc.is_event_triggered(RtcInterrupt::Compare3) {
+            rtc.reset_event(RtcInterrupt::Compare3);
+            TICKER.period.fetch_add(1, Ordering::SeqCst);
+        }
+        if rtc.is_event_triggered(RtcInterrupt::Compare0) {
+            rtc.reset_event(RtcInterrupt::Compare0);
+        }
+
+        drop(rm_rtc);
+
+        // For all of these events, schedule the next wakeup: a new half-period
+        // might have brought a far-off deadline within reach. This should also
+        // kill enough clock cycles to allow the event flags to clear.
+        // (see nRF52833 Product Specification section 6.1.8)
+        schedule_wakeup(WAKE_DEADLINES.borrow_ref_mut(cs), cs);
+    });
+}
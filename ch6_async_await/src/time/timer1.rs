@@ -0,0 +1,156 @@
+use core::{
+    cell::{RefCell, RefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use critical_section::{CriticalSection, Mutex};
+use microbit::pac::{interrupt, Interrupt, NVIC, TIMER1};
+
+use super::{TickInstant, TimerQueue, WAKE_DEADLINES};
+
+/// TIMER1 counts at a fixed 1MHz, so ticks are microseconds.
+pub const TICK_HZ: u32 = 1_000_000;
+
+/// TIMER1 runs off the 16MHz HFCLK, divided by 2^PRESCALER
+const PRESCALER: u8 = 4;
+
+/// The compare channels used by the backend:
+/// - CC[0]: the next deadline
+/// - CC[1]: scratch space for reading the counter (via the CAPTURE task)
+/// - CC[2] & CC[3]: fixed at 0 and halfway, for counting half-periods
+const ALARM: usize = 0;
+const CAPTURE: usize = 1;
+const WRAP: usize = 2;
+const HALFWAY: usize = 3;
+
+/// Ticks a deadline must be ahead of the counter before it's worth setting an
+/// alarm for: anything closer just gets woken straight away.
+const MIN_LEAD: u64 = 4;
+
+/// Same as the RTC backend: a deadline only goes in CC[0] once it's within 3/4
+/// of a lap, and anything further off gets picked up by a later half-period
+/// event.
+pub(super) fn schedule_wakeup(mut rm_deadlines: RefMut<TimerQueue>, cs: CriticalSection) {
+    let rm_timer = TICKER.timer.borrow_ref(cs);
+    let timer = rm_timer.as_ref().unwrap();
+    while let Some(deadline) = rm_deadlines.peek() {
+        let now = calc_now(TICKER.period.load(Ordering::Relaxed), capture(timer));
+        if deadline > now + MIN_LEAD {
+            if deadline - now < 0xC000_0000 {
+                timer.cc[ALARM].write(|w| unsafe { w.cc().bits(deadline as u32) });
+                timer.intenset.write(|w| w.compare0().set());
+                // Unlike the RTC, TIMER has no minimum distance for COMPARE to
+                // work, so the only worry is the counter getting past the
+                // deadline while we were setting it: check again, and fall
+                // through to waking it here if so.
+                let now = calc_now(TICKER.period.load(Ordering::Relaxed), capture(timer));
+                if deadline > now {
+                    break;
+                }
+            } else {
+                timer.intenclr.write(|w| w.compare0().clear());
+                break;
+            }
+        }
+        // Wake now if it's too close or already past,
+        // then try again with the next available deadline
+        if let Some(target) = rm_deadlines.pop() {
+            target.wake();
+        }
+    }
+    if rm_deadlines.is_empty() {
+        timer.intenclr.write(|w| w.compare0().clear());
+    }
+}
+
+static TICKER: Ticker = Ticker {
+    period: AtomicU32::new(0),
+    timer: Mutex::new(RefCell::new(None)),
+};
+
+/// Keeps track of time for the system using TIMER1, counting microseconds.
+/// That's about 30x finer than the RTC, but TIMER1 needs the HFCLK (and so a
+/// lot more power) running the whole time, even while the core sleeps.
+///
+/// Apart from that it works the same way as the RTC backend: TIMER1 is put in
+/// 32-bit mode, and CC[2] & CC[3] are parked at the wrap-around point and the
+/// halfway mark so `period` counts half laps of the counter.
+pub struct Ticker {
+    period: AtomicU32,
+    timer: Mutex<RefCell<Option<TIMER1>>>,
+}
+
+/// Combines a `period` and a counter value into a 64-bit tick count, flipping
+/// the counter's MSB if it's already moved on into the next half-period.
+fn calc_now(period: u32, counter: u32) -> u64 {
+    ((period as u64) << 31) + (counter ^ ((period & 1) << 31)) as u64
+}
+
+/// There's no COUNTER register to read on a TIMER, so the current value has to
+/// be copied into a CC register first.
+fn capture(timer: &TIMER1) -> u32 {
+    timer.tasks_capture[CAPTURE].write(|w| unsafe { w.bits(1) });
+    timer.cc[CAPTURE].read().cc().bits()
+}
+
+impl Ticker {
+    /// Called on startup to get TIMER1 going, then hoists it into the
+    /// `static TICKER`, where it can be accessed by the interrupt handler
+    /// function or any `Timer` instance.
+    ///
+    /// A COMPARE event only fires when the counter *increments* onto the CC
+    /// value, so CC[2] doesn't go off straight away for the starting 0.
+    pub fn init(timer1: TIMER1, _nvic: &mut NVIC) {
+        timer1.tasks_stop.write(|w| unsafe { w.bits(1) });
+        timer1.tasks_clear.write(|w| unsafe { w.bits(1) });
+        timer1.mode.write(|w| w.mode().timer());
+        timer1.bitmode.write(|w| w.bitmode()._32bit());
+        timer1.prescaler.write(|w| unsafe { w.prescaler().bits(PRESCALER) });
+        timer1.cc[WRAP].write(|w| unsafe { w.cc().bits(0) });
+        timer1.cc[HALFWAY].write(|w| unsafe { w.cc().bits(0x8000_0000) });
+        timer1.intenset.write(|w| w.compare2().set().compare3().set());
+        timer1.tasks_start.write(|w| unsafe { w.bits(1) });
+        critical_section::with(|cs| {
+            TICKER.timer.replace(cs, Some(timer1));
+        });
+        unsafe { NVIC::unmask(Interrupt::TIMER1); }
+    }
+
+    /// Get the current time, from the half-period count (upper 33 bits) and
+    /// the counter value (lowest 31 bits).
+    ///
+    /// `period` has to be read before the counter: read the other way round,
+    /// an interrupt in between could bump `period` for a counter value that's
+    /// already out of date.
+    pub fn now() -> TickInstant {
+        let period = TICKER.period.load(Ordering::SeqCst);
+        let counter = critical_section::with(|cs| {
+            capture(TICKER.timer.borrow_ref(cs).as_ref().unwrap())
+        });
+        TickInstant::from_ticks(calc_now(period, counter))
+    }
+}
+
+#[interrupt]
+fn TIMER1() {
+    critical_section::with(|cs| {
+        let rm_timer = TICKER.timer.borrow_ref(cs);
+        let timer = rm_timer.as_ref().unwrap();
+        for channel in [WRAP, HALFWAY] {
+            if timer.events_compare[channel].read().bits() != 0 {
+                timer.events_compare[channel].write(|w| unsafe { w.bits(0) });
+                TICKER.period.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        if timer.events_compare[ALARM].read().bits() != 0 {
+            timer.events_compare[ALARM].write(|w| unsafe { w.bits(0) });
+        }
+        drop(rm_timer);
+
+        // For all of these events, schedule the next wakeup: a new half-period
+        // might have brought a far-off deadline within reach. This should also
+        // kill enough clock cycles to allow the event flags to clear.
+        // (see nRF52833 Product Specification section 6.1.8)
+        schedule_wakeup(WAKE_DEADLINES.borrow_ref_mut(cs), cs);
+    });
+}